@@ -0,0 +1,38 @@
+//! Times timers while recording is off against reading the clock, to check
+//! disabled timers cost a flag check rather than a clock read. Run with
+//! `cargo bench`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use simple_tracing::{InstrumentationTimer, Instrumentor};
+
+const ITERATIONS: u32 = 10_000_000;
+
+fn per_iteration(mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    Instrumentor::set_enabled(false);
+    let disabled = per_iteration(|| {
+        black_box(InstrumentationTimer::new(black_box("span")));
+    });
+    let clock = per_iteration(|| {
+        black_box(Instant::now());
+    });
+    Instrumentor::set_enabled(true);
+
+    println!("disabled timer: {disabled:?}");
+    println!("Instant::now(): {clock:?}");
+    if disabled >= clock {
+        println!("disabled timers cost as much as a clock read");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,72 @@
+//! Per-thread allocation counting for [`TracingAllocator`].
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    cell::Cell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Whether a [`TracingAllocator`] is installed, i.e. has served an allocation.
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Bytes allocated by this thread so far. A `const` `Cell` needs no lazy
+    /// initialization, so the allocator can use it without allocating.
+    static ALLOCATED: Cell<u64> = const { Cell::new(0) };
+}
+
+/// A global allocator wrapping `A` that counts the bytes each thread
+/// allocates, so every span gets an `alloc_bytes` arg with the bytes its
+/// thread allocated while it ran. Install it in a `#[global_allocator]`
+/// static, e.g. wrapping `std::alloc::System`.
+///
+/// Growing an allocation counts the bytes it grew by, freeing isn't
+/// subtracted.
+pub struct TracingAllocator<A> {
+    inner: A,
+}
+
+impl<A> TracingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        TracingAllocator { inner }
+    }
+}
+
+fn count(bytes: usize) {
+    if !INSTALLED.load(Ordering::Relaxed) {
+        INSTALLED.store(true, Ordering::Relaxed);
+    }
+    // Fails once the thread's locals are destroyed, those bytes can't belong
+    // to a span anymore anyway.
+    let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + bytes as u64));
+}
+
+/// Bytes allocated by the calling thread so far, `None` without a
+/// [`TracingAllocator`].
+pub(crate) fn allocated() -> Option<u64> {
+    if !INSTALLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    ALLOCATED.try_with(Cell::get).ok()
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TracingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size.saturating_sub(layout.size()));
+        self.inner.realloc(ptr, layout, new_size)
+    }
+}
@@ -0,0 +1,134 @@
+//! Analyses over the events recorded by a session, or read from traces.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+};
+
+use crate::{
+    histogram::csv_field,
+    reader::{self, TraceReader},
+    NameDiff, NameStats, ProfileResult,
+};
+
+/// The heaviest root-to-leaf chain of nested spans: the longest top-level
+/// span, then its longest child, and so on down to a span with no children.
+pub(crate) fn critical_path(events: &[ProfileResult]) -> Vec<ProfileResult> {
+    // Rebuild the span tree of each thread. Sorting by start puts a parent
+    // before its children, and depth breaks the tie when both start in the
+    // same microsecond.
+    let mut order: Vec<usize> = (0..events.len()).collect();
+    order.sort_by_key(|&i| (events[i].thread_id, events[i].start, events[i].depth));
+
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); events.len()];
+    let mut roots = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+    let mut thread_id = None;
+    for i in order {
+        let event = &events[i];
+        if thread_id != Some(event.thread_id) {
+            open.clear();
+            thread_id = Some(event.thread_id);
+        }
+        while open
+            .last()
+            .is_some_and(|&top| events[top].depth >= event.depth)
+        {
+            open.pop();
+        }
+        match open.last() {
+            Some(&parent) => children[parent].push(i),
+            None => roots.push(i),
+        }
+        open.push(i);
+    }
+
+    let duration = |&i: &usize| events[i].duration();
+    let mut path = Vec::new();
+    let mut next = roots.iter().copied().max_by_key(duration);
+    while let Some(i) = next {
+        path.push(events[i].clone());
+        next = children[i].iter().copied().max_by_key(duration);
+    }
+    path
+}
+
+/// See [`crate::Instrumentor::diff_traces`].
+pub(crate) fn diff_traces(baseline_path: &str, candidate_path: &str) -> io::Result<Vec<NameDiff>> {
+    let baseline = trace_summary(baseline_path)?;
+    let candidate = trace_summary(candidate_path)?;
+    let names: BTreeSet<&String> = baseline.keys().chain(candidate.keys()).collect();
+
+    let mut diffs: Vec<NameDiff> = names
+        .into_iter()
+        .map(|name| {
+            let baseline = baseline.get(name).copied().unwrap_or_default();
+            let candidate = candidate.get(name).copied().unwrap_or_default();
+            let total_delta = candidate.total - baseline.total;
+            NameDiff {
+                name: name.clone(),
+                baseline,
+                candidate,
+                total_delta,
+                mean_delta: candidate.mean() - baseline.mean(),
+                total_change: (baseline.total != 0)
+                    .then(|| total_delta as f64 / baseline.total as f64 * 100.0),
+            }
+        })
+        .collect();
+    // The names are already sorted, and the sort is stable.
+    diffs.sort_by_key(|diff| std::cmp::Reverse(diff.total_delta.unsigned_abs()));
+    Ok(diffs)
+}
+
+/// See [`crate::Instrumentor::write_diff_csv`].
+pub(crate) fn write_diff_csv(diffs: &[NameDiff], path: &str) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(
+        out,
+        "name,baseline_count,candidate_count,baseline_total_us,candidate_total_us,total_delta_us,total_change_pct,baseline_mean_us,candidate_mean_us,mean_delta_us"
+    )?;
+    for diff in diffs {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{},{}",
+            csv_field(&diff.name),
+            diff.baseline.count,
+            diff.candidate.count,
+            diff.baseline.total,
+            diff.candidate.total,
+            diff.total_delta,
+            diff.total_change
+                .map_or(String::new(), |change| format!("{change:.1}")),
+            diff.baseline.mean(),
+            diff.candidate.mean(),
+            diff.mean_delta,
+        )?;
+    }
+    out.flush()
+}
+
+/// Stats of the complete (`"ph":"X"`) events of the JSON trace at `path`, by
+/// name.
+fn trace_summary(path: &str) -> io::Result<HashMap<String, NameStats>> {
+    let mut trace = TraceReader::new(BufReader::new(File::open(path)?))?;
+    let mut summary: HashMap<String, NameStats> = HashMap::new();
+    while let Some(event) = trace.next_event()? {
+        if reader::field(&event, "ph") != Some("\"X\"") {
+            continue;
+        }
+        let (Some(name), Some(dur)) = (reader::field(&event, "name"), reader::field(&event, "dur"))
+        else {
+            continue;
+        };
+        let Ok(dur) = dur.parse::<f64>() else {
+            continue;
+        };
+        summary
+            .entry(reader::string(name)?)
+            .or_default()
+            .record(dur as i64);
+    }
+    Ok(summary)
+}
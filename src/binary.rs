@@ -0,0 +1,197 @@
+//! Compact binary sink for [`crate::TraceFormat::Binary`] and its converter
+//! to JSON.
+//!
+//! A trace is the magic `STRC` and a little-endian `u32` schema version,
+//! followed by records each starting with a tag byte:
+//!
+//! - `0`, a string: `u32` id, `u32` length, UTF-8 bytes. Written right before
+//!   its first use, so a trace cut off by a crash is still readable.
+//! - `1` and `3`, a function or idle span: `u32` name id, `i64` ts, `i64` dur
+//!   and `u32` tid, all fixed size.
+//! - `2`, a thread name: `u32` tid, `u32` name id.
+//!
+//! Args aren't recorded.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+};
+
+use crate::{write_chrome_header, write_thread_name_json, ProfileResult, SCHEMA_VERSION};
+
+const MAGIC: &[u8; 4] = b"STRC";
+
+const STRING: u8 = 0;
+const FUNCTION: u8 = 1;
+const THREAD_NAME: u8 = 2;
+const IDLE: u8 = 3;
+
+/// Size of a span record, without the strings it refers to.
+pub(crate) const SPAN_LEN: usize = 1 + 4 + 8 + 8 + 4;
+
+/// Per-session state for the binary sink.
+#[derive(Default)]
+pub(crate) struct BinaryState {
+    names: HashMap<String, u32>,
+}
+
+impl BinaryState {
+    pub(crate) fn write_header(&mut self, stream: &mut impl Write) -> io::Result<()> {
+        self.names.clear();
+        stream.write_all(MAGIC)?;
+        stream.write_all(&SCHEMA_VERSION.to_le_bytes())
+    }
+
+    pub(crate) fn write_thread_name(
+        &mut self,
+        stream: &mut impl Write,
+        thread_id: u32,
+        name: &str,
+    ) -> io::Result<()> {
+        let name_id = self.name_id(stream, name)?;
+        stream.write_all(&[THREAD_NAME])?;
+        stream.write_all(&thread_id.to_le_bytes())?;
+        stream.write_all(&name_id.to_le_bytes())
+    }
+
+    pub(crate) fn write_span(
+        &mut self,
+        stream: &mut impl Write,
+        result: &ProfileResult,
+        category: &str,
+    ) -> io::Result<()> {
+        let name_id = self.name_id(stream, &result.name)?;
+        let mut record = [0; SPAN_LEN];
+        record[0] = if category == "idle" { IDLE } else { FUNCTION };
+        record[1..5].copy_from_slice(&name_id.to_le_bytes());
+        record[5..13].copy_from_slice(&result.start.to_le_bytes());
+        record[13..21].copy_from_slice(&result.duration().to_le_bytes());
+        record[21..25].copy_from_slice(&result.thread_id.to_le_bytes());
+        stream.write_all(&record)
+    }
+
+    /// Size of the records written for `spans` and their categories, with
+    /// the string table entries of names not seen yet.
+    pub(crate) fn spans_len(&self, spans: &[(&ProfileResult, &str)]) -> usize {
+        let mut new_names: Vec<&str> = Vec::new();
+        for (result, _) in spans {
+            if !self.names.contains_key(&result.name) && !new_names.contains(&&*result.name) {
+                new_names.push(&result.name);
+            }
+        }
+        let strings: usize = new_names.iter().map(|name| 1 + 4 + 4 + name.len()).sum();
+        strings + spans.len() * SPAN_LEN
+    }
+
+    /// The id of `name`, writing it to the string table the first time.
+    fn name_id(&mut self, stream: &mut impl Write, name: &str) -> io::Result<u32> {
+        if let Some(&id) = self.names.get(name) {
+            return Ok(id);
+        }
+        let id = self.names.len() as u32;
+        stream.write_all(&[STRING])?;
+        stream.write_all(&id.to_le_bytes())?;
+        stream.write_all(&(name.len() as u32).to_le_bytes())?;
+        stream.write_all(name.as_bytes())?;
+        self.names.insert(name.to_string(), id);
+        Ok(id)
+    }
+}
+
+/// Expands the binary trace at `in_path` into a JSON trace at `out_path`.
+pub(crate) fn binary_to_chrome(in_path: &str, out_path: &str) -> io::Result<()> {
+    let mut input = BufReader::new(File::open(in_path)?);
+    let mut magic = [0; 4];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a binary trace"));
+    }
+    let version = read_u32(&mut input)?;
+
+    let mut out = BufWriter::new(File::create(out_path)?);
+    write_chrome_header(&mut out, version, None, false)?;
+
+    let mut names: Vec<String> = Vec::new();
+    let mut first = true;
+    let mut tag = [0];
+    // A trace cut off mid-record is converted up to the last whole record.
+    while input.read(&mut tag)? == 1 {
+        let name = |names: &[String], id: u32| {
+            names
+                .get(id as usize)
+                .cloned()
+                .ok_or_else(|| invalid("unknown string id"))
+        };
+        match tag[0] {
+            STRING => {
+                let Ok(id) = read_u32(&mut input) else { break };
+                let Ok(len) = read_u32(&mut input) else { break };
+                // The length may be garbage, so it's only trusted as far as
+                // the file goes.
+                let mut bytes = Vec::new();
+                (&mut input).take(len as u64).read_to_end(&mut bytes)?;
+                if bytes.len() != len as usize {
+                    break;
+                }
+                if id as usize != names.len() {
+                    return Err(invalid("string ids out of order"));
+                }
+                names.push(String::from_utf8(bytes).map_err(|_| invalid("invalid string"))?);
+            }
+            THREAD_NAME => {
+                let Ok(thread_id) = read_u32(&mut input) else {
+                    break;
+                };
+                let Ok(name_id) = read_u32(&mut input) else {
+                    break;
+                };
+                let thread_name = name(&names, name_id)?;
+                separate(&mut out, &mut first)?;
+                write_thread_name_json(&mut out, thread_id, &thread_name, &[])?;
+            }
+            FUNCTION | IDLE => {
+                let mut record = [0; SPAN_LEN - 1];
+                if input.read_exact(&mut record).is_err() {
+                    break;
+                }
+                let start = i64::from_le_bytes(record[4..12].try_into().unwrap());
+                let duration = i64::from_le_bytes(record[12..20].try_into().unwrap());
+                let result = ProfileResult {
+                    name: name(&names, u32::from_le_bytes(record[0..4].try_into().unwrap()))?,
+                    start,
+                    end: start.saturating_add(duration),
+                    thread_id: u32::from_le_bytes(record[20..24].try_into().unwrap()),
+                    depth: 0,
+                    args: Vec::new(),
+                    tags: Vec::new(),
+                };
+                let category = if tag[0] == IDLE { "idle" } else { "function" };
+                separate(&mut out, &mut first)?;
+                result.write_chrome_json(&mut out, category)?;
+            }
+            _ => return Err(invalid("unknown record")),
+        }
+    }
+
+    write!(out, "]}}")?;
+    out.flush()
+}
+
+fn separate(out: &mut impl Write, first: &mut bool) -> io::Result<()> {
+    if !*first {
+        write!(out, ",")?;
+    }
+    *first = false;
+    Ok(())
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,253 @@
+//! Span events for Event Tracing for Windows.
+//!
+//! Spans are written as TraceLogging events of the provider `SimpleTracing`,
+//! which carry their own metadata so Windows Performance Analyzer and
+//! `tracerpt` decode them without a manifest. A span's begin and end are
+//! `SpanBegin` and `SpanEnd` events with the start and stop opcodes and the
+//! span's `name`. Record them by enabling the provider's GUID, e.g. with
+//! `tracelog -start st -guid #3c5b8f2e-9d4a-4e71-b6a3-5f0c2d8e1a47`.
+
+use std::{
+    ffi::c_void,
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+#[repr(C)]
+struct Guid {
+    data1: u32,
+    data2: u16,
+    data3: u16,
+    data4: [u8; 8],
+}
+
+#[repr(C)]
+struct EventDescriptor {
+    id: u16,
+    version: u8,
+    channel: u8,
+    level: u8,
+    opcode: u8,
+    task: u16,
+    keyword: u64,
+}
+
+#[repr(C)]
+struct EventDataDescriptor {
+    ptr: u64,
+    size: u32,
+    /// The descriptor's type: 0 for data, or one of the metadata types below.
+    kind: u32,
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn EventRegister(
+        provider_id: *const Guid,
+        enable_callback: *const c_void,
+        callback_context: *mut c_void,
+        reg_handle: *mut u64,
+    ) -> u32;
+    fn EventUnregister(reg_handle: u64) -> u32;
+    fn EventSetInformation(
+        reg_handle: u64,
+        information_class: i32,
+        information: *const c_void,
+        information_length: u32,
+    ) -> u32;
+    fn EventEnabled(reg_handle: u64, descriptor: *const EventDescriptor) -> u8;
+    fn EventWrite(
+        reg_handle: u64,
+        descriptor: *const EventDescriptor,
+        user_data_count: u32,
+        user_data: *const EventDataDescriptor,
+    ) -> u32;
+}
+
+/// `{3c5b8f2e-9d4a-4e71-b6a3-5f0c2d8e1a47}`
+const PROVIDER_ID: Guid = Guid {
+    data1: 0x3c5b_8f2e,
+    data2: 0x9d4a,
+    data3: 0x4e71,
+    data4: [0xb6, 0xa3, 0x5f, 0x0c, 0x2d, 0x8e, 0x1a, 0x47],
+};
+const PROVIDER_NAME: &str = "SimpleTracing";
+
+const EVENT_PROVIDER_SET_TRAITS: i32 = 2;
+const EVENT_DATA_DESCRIPTOR_TYPE_EVENT_METADATA: u32 = 1;
+const EVENT_DATA_DESCRIPTOR_TYPE_PROVIDER_METADATA: u32 = 2;
+/// The channel marking an event as TraceLogging.
+const TRACELOGGING_CHANNEL: u8 = 11;
+const LEVEL_VERBOSE: u8 = 5;
+const OPCODE_START: u8 = 1;
+const OPCODE_STOP: u8 = 2;
+/// A NUL-terminated 8-bit string, followed by its out type.
+const IN_TYPE_ANSI_STRING_CHAINED: u8 = 2 | 0x80;
+const OUT_TYPE_UTF8: u8 = 35;
+
+struct Provider {
+    handle: u64,
+    provider_metadata: Vec<u8>,
+    begin_metadata: Vec<u8>,
+    end_metadata: Vec<u8>,
+}
+
+impl Drop for Provider {
+    fn drop(&mut self) {
+        // SAFETY: the handle was registered by `enable` and is dropped here.
+        unsafe {
+            EventUnregister(self.handle);
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static PROVIDER: Mutex<Option<Arc<Provider>>> = Mutex::new(None);
+
+/// A span begun by [`begin`] whose `SpanBegin` was written, ended with the
+/// provider it was begun with even if ETW was disabled or re-enabled since.
+pub(crate) struct Interval {
+    provider: Arc<Provider>,
+}
+
+pub(crate) fn enable() -> io::Result<()> {
+    let mut provider = PROVIDER.lock().unwrap_or_else(PoisonError::into_inner);
+    if provider.is_some() {
+        return Ok(());
+    }
+    let mut handle = 0;
+    // SAFETY: the GUID outlives the call and `handle` is a valid out pointer.
+    let status = unsafe {
+        EventRegister(
+            &PROVIDER_ID,
+            std::ptr::null(),
+            std::ptr::null_mut(),
+            &mut handle,
+        )
+    };
+    if status != 0 {
+        return Err(io::Error::from_raw_os_error(status as i32));
+    }
+    let provider_metadata = metadata(&[PROVIDER_NAME.as_bytes(), &[0]]);
+    // SAFETY: the traits are read during the call. Failing to set them only
+    // leaves the provider's name out of the events.
+    unsafe {
+        EventSetInformation(
+            handle,
+            EVENT_PROVIDER_SET_TRAITS,
+            provider_metadata.as_ptr().cast(),
+            provider_metadata.len() as u32,
+        );
+    }
+    *provider = Some(Arc::new(Provider {
+        handle,
+        provider_metadata,
+        begin_metadata: event_metadata("SpanBegin"),
+        end_metadata: event_metadata("SpanEnd"),
+    }));
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// The provider is unregistered once the intervals begun with it ended.
+pub(crate) fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+    PROVIDER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+}
+
+/// `None` while disabled or while no trace session enables the provider, so
+/// a `SpanEnd` is only ever written after its `SpanBegin`.
+pub(crate) fn begin(name: &str) -> Option<Interval> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let provider = PROVIDER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()?;
+    write_event(&provider, OPCODE_START, name).then_some(Interval { provider })
+}
+
+pub(crate) fn end(interval: Interval, name: &str) {
+    write_event(&interval.provider, OPCODE_STOP, name);
+}
+
+/// Returns whether the event was written, i.e. a trace session enables it.
+/// Failures are ignored, like for the other span markers.
+fn write_event(provider: &Provider, opcode: u8, name: &str) -> bool {
+    let descriptor = EventDescriptor {
+        id: 0,
+        version: 0,
+        channel: TRACELOGGING_CHANNEL,
+        level: LEVEL_VERBOSE,
+        opcode,
+        task: 0,
+        keyword: 0,
+    };
+    // SAFETY: the descriptor is valid for the call.
+    if unsafe { EventEnabled(provider.handle, &descriptor) } == 0 {
+        return false;
+    }
+    let event_metadata = match opcode {
+        OPCODE_START => &provider.begin_metadata,
+        _ => &provider.end_metadata,
+    };
+    let mut name = name.as_bytes().to_vec();
+    name.push(0);
+    let data = [
+        data_descriptor(
+            &provider.provider_metadata,
+            EVENT_DATA_DESCRIPTOR_TYPE_PROVIDER_METADATA,
+        ),
+        data_descriptor(event_metadata, EVENT_DATA_DESCRIPTOR_TYPE_EVENT_METADATA),
+        data_descriptor(&name, 0),
+    ];
+    // SAFETY: every descriptor points into a buffer that outlives the call.
+    unsafe {
+        EventWrite(
+            provider.handle,
+            &descriptor,
+            data.len() as u32,
+            data.as_ptr(),
+        );
+    }
+    true
+}
+
+fn data_descriptor(bytes: &[u8], kind: u32) -> EventDataDescriptor {
+    EventDataDescriptor {
+        ptr: bytes.as_ptr() as u64,
+        size: bytes.len() as u32,
+        kind,
+    }
+}
+
+/// An event named `name` with a single UTF-8 string field `name`. The byte
+/// after the size holds the event's tags, none here.
+fn event_metadata(name: &str) -> Vec<u8> {
+    metadata(&[
+        &[0],
+        name.as_bytes(),
+        &[0],
+        b"name",
+        &[0, IN_TYPE_ANSI_STRING_CHAINED, OUT_TYPE_UTF8],
+    ])
+}
+
+/// TraceLogging metadata: its own size as a little-endian `u16`, followed by
+/// `parts`.
+fn metadata(parts: &[&[u8]]) -> Vec<u8> {
+    let mut metadata = vec![0, 0];
+    for part in parts {
+        metadata.extend_from_slice(part);
+    }
+    let len = (metadata.len() as u16).to_le_bytes();
+    metadata[..2].copy_from_slice(&len);
+    metadata
+}
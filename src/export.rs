@@ -0,0 +1,175 @@
+//! Exporting traces into other shapes.
+
+use std::{fs, io};
+
+const PERFETTO_ORIGIN: &str = "https://ui.perfetto.dev";
+
+/// Draws the spans of the embedded trace as one flame chart per thread,
+/// without anything from the network.
+const VIEWER_SCRIPT: &str = r#"
+const data = JSON.parse(trace);
+const events = Array.isArray(data) ? data : data.traceEvents || [];
+const threads = new Map();
+const threadNames = new Map();
+const thread = (event) => {
+  const key = event.pid + ":" + event.tid;
+  if (!threads.has(key)) threads.set(key, { pid: event.pid, tid: event.tid, spans: [], open: [] });
+  return threads.get(key);
+};
+for (const event of events) {
+  if (event.ph === "M" && event.name === "thread_name") {
+    threadNames.set(event.pid + ":" + event.tid, event.args.name);
+  } else if (event.ph === "X") {
+    thread(event).spans.push({ name: event.name, ts: event.ts, dur: event.dur || 0, args: event.args });
+  } else if (event.ph === "B") {
+    thread(event).open.push(event);
+  } else if (event.ph === "E") {
+    const begin = thread(event).open.pop();
+    if (begin) {
+      thread(event).spans.push({ name: begin.name, ts: begin.ts, dur: event.ts - begin.ts, args: begin.args });
+    }
+  }
+}
+
+let start = Infinity;
+let end = -Infinity;
+for (const { spans } of threads.values()) {
+  for (const span of spans) {
+    start = Math.min(start, span.ts);
+    end = Math.max(end, span.ts + span.dur);
+  }
+}
+const length = Math.max(end - start, 1);
+const rowHeight = 18;
+const color = (name) => {
+  let hash = 0;
+  for (const c of name) hash = (hash * 31 + c.charCodeAt(0)) | 0;
+  return "hsl(" + (Math.abs(hash) % 360) + ", 55%, 72%)";
+};
+
+const viewer = document.getElementById("viewer");
+const lanes = document.createElement("div");
+viewer.append(lanes);
+const sorted = [...threads.entries()].sort(([, a], [, b]) => a.pid - b.pid || a.tid - b.tid);
+for (const [key, { tid, spans }] of sorted) {
+  if (spans.length === 0) continue;
+  const heading = document.createElement("h2");
+  heading.textContent = threadNames.get(key) || "thread " + tid;
+  const lane = document.createElement("div");
+  lane.className = "lane";
+  // Outer spans first, so every span is drawn below the ones it's inside.
+  spans.sort((a, b) => a.ts - b.ts || b.dur - a.dur);
+  const open = [];
+  let depth = 0;
+  for (const span of spans) {
+    while (open.length && open[open.length - 1] <= span.ts) open.pop();
+    const block = document.createElement("div");
+    block.className = "span";
+    block.style.left = ((span.ts - start) / length) * 100 + "%";
+    block.style.width = (span.dur / length) * 100 + "%";
+    block.style.top = open.length * rowHeight + "px";
+    block.style.background = color(span.name);
+    block.textContent = span.name;
+    block.title = span.name + "\n" + span.dur + " µs" + (span.args ? "\n" + JSON.stringify(span.args) : "");
+    lane.append(block);
+    open.push(span.ts + span.dur);
+    depth = Math.max(depth, open.length);
+  }
+  lane.style.height = depth * rowHeight + "px";
+  lanes.append(heading, lane);
+}
+
+const zoom = document.getElementById("zoom");
+zoom.oninput = () => {
+  lanes.style.width = zoom.value * 100 + "%";
+};
+"#;
+
+/// Wraps the JSON trace at `trace_path` in a standalone HTML page at
+/// `html_path`.
+pub(crate) fn export_html(trace_path: &str, html_path: &str) -> io::Result<()> {
+    let trace = fs::read_to_string(trace_path)?;
+    let title = trace_path.rsplit(['/', '\\']).next().unwrap_or(trace_path);
+
+    // These can only appear inside JSON strings, where the escapes mean the
+    // same thing. Escaping them all keeps names like "</script>" or
+    // "<!--<script" from closing the script block or keeping it open.
+    let trace = trace
+        .replace('&', "\\u0026")
+        .replace('<', "\\u003c")
+        .replace('>', "\\u003e");
+    let title = title
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;");
+
+    fs::write(
+        html_path,
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; }}
+h2 {{ font-size: 14px; margin: 12px 0 4px; }}
+#viewer {{ overflow-x: auto; }}
+.lane {{ position: relative; }}
+.span {{
+  position: absolute;
+  box-sizing: border-box;
+  height: 17px;
+  overflow: hidden;
+  white-space: nowrap;
+  font-size: 11px;
+  line-height: 17px;
+  padding-left: 2px;
+  border-right: 1px solid white;
+}}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+<p>
+  <label>Zoom <input id="zoom" type="range" min="1" max="100" value="1"></label>
+  <button id="download">Download JSON</button>
+  <button id="open">Open in Perfetto UI</button>
+</p>
+<p>
+  Hover a span for its duration and args. The downloaded file can also be
+  loaded into chrome://tracing, or opened at {PERFETTO_ORIGIN}, which shows
+  counters, flows and samples too. The Perfetto button needs network access.
+</p>
+<div id="viewer"></div>
+<script type="application/json" id="trace">{trace}</script>
+<script>
+const trace = document.getElementById("trace").textContent;
+const title = document.title;
+{VIEWER_SCRIPT}
+document.getElementById("download").onclick = () => {{
+  const link = document.createElement("a");
+  link.href = URL.createObjectURL(new Blob([trace], {{ type: "application/json" }}));
+  link.download = title;
+  link.click();
+}};
+
+document.getElementById("open").onclick = () => {{
+  const ui = window.open("{PERFETTO_ORIGIN}/#!/");
+  const ping = setInterval(() => ui.postMessage("PING", "{PERFETTO_ORIGIN}"), 50);
+  window.addEventListener("message", function onMessage(event) {{
+    if (event.origin !== "{PERFETTO_ORIGIN}" || event.data !== "PONG") return;
+    clearInterval(ping);
+    window.removeEventListener("message", onMessage);
+    const buffer = new TextEncoder().encode(trace).buffer;
+    ui.postMessage({{ perfetto: {{ buffer, title }} }}, "{PERFETTO_ORIGIN}");
+  }});
+}};
+</script>
+</body>
+</html>
+"#
+        ),
+    )
+}
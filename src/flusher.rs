@@ -0,0 +1,33 @@
+//! Background thread flushing the session's output on a fixed interval.
+
+use std::{
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::Instrumentor;
+
+pub(crate) struct Flusher {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Flusher {
+    pub(crate) fn spawn(interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                Instrumentor::flush();
+            }
+        });
+        Flusher { stop, handle }
+    }
+
+    /// Stops the flusher and waits for its thread to exit. Must not be called
+    /// with the instrumentor locked, the flusher may be waiting on it.
+    pub(crate) fn stop(self) {
+        drop(self.stop);
+        self.handle.join().unwrap();
+    }
+}
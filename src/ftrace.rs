@@ -0,0 +1,89 @@
+//! Span markers for the Linux kernel's `ftrace`.
+//!
+//! Lines written to tracefs' `trace_marker` show up interleaved with kernel
+//! events in `trace-cmd` and `kernelshark`. `B|pid|name` and `E|pid` lines are
+//! shown as the begin and end of a slice, like systrace does.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+};
+
+const TRACE_MARKER_PATHS: [&str; 2] = [
+    "/sys/kernel/tracing/trace_marker",
+    "/sys/kernel/debug/tracing/trace_marker",
+];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Read on every span, so a lock poisoned by some panic elsewhere is
+/// recovered from rather than taking the span down with it.
+static TRACE_MARKER: RwLock<Option<Arc<File>>> = RwLock::new(None);
+
+/// A slice begun by [`begin`], ended in the `trace_marker` it was begun in
+/// even if markers were disabled or re-enabled since.
+pub(crate) struct Slice {
+    trace_marker: Arc<File>,
+}
+
+pub(crate) fn enable() -> io::Result<()> {
+    let mut last_err = None;
+    for path in TRACE_MARKER_PATHS {
+        match OpenOptions::new().write(true).open(path) {
+            Ok(file) => {
+                *TRACE_MARKER.write().unwrap_or_else(PoisonError::into_inner) =
+                    Some(Arc::new(file));
+                ENABLED.store(true, Ordering::Relaxed);
+                return Ok(());
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    let err = last_err.unwrap();
+    Err(io::Error::new(
+        err.kind(),
+        format!("can't open trace_marker, is tracefs mounted and writable? ({err})"),
+    ))
+}
+
+pub(crate) fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+    *TRACE_MARKER.write().unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+/// `None` while disabled. An `E` is only ever written for a [`Slice`], so
+/// it can't close a slice some other code began.
+pub(crate) fn begin(name: &str) -> Option<Slice> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let trace_marker = TRACE_MARKER
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()?;
+    write_marker(&trace_marker, &begin_marker(name));
+    Some(Slice { trace_marker })
+}
+
+/// The `B` line for `name`. Every marker is a single line, so line breaks in
+/// the name are written as spaces.
+pub(crate) fn begin_marker(name: &str) -> String {
+    format!(
+        "B|{}|{}",
+        std::process::id(),
+        name.replace(['\n', '\r'], " ")
+    )
+}
+
+pub(crate) fn end(slice: Slice) {
+    write_marker(&slice.trace_marker, &format!("E|{}", std::process::id()));
+}
+
+/// Each marker has to go out in a single write. Failures are ignored, a
+/// missing marker isn't worth failing the span over.
+fn write_marker(mut trace_marker: &File, marker: &str) {
+    let _ = trace_marker.write_all(marker.as_bytes());
+}
@@ -0,0 +1,125 @@
+//! Fixed-size duration histograms for percentiles.
+//!
+//! Buckets are log-linear like HDR histograms: values below 16 get a bucket
+//! each, above that every power of two is split into 16 buckets. That keeps
+//! every percentile within about 6% of the true value while a histogram never
+//! holds more than a thousand counters, however many spans it sees.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+const SUB_BUCKETS: u64 = 16;
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+
+/// Approximate span duration percentiles in microseconds, see
+/// [`crate::Instrumentor::percentiles`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Percentiles {
+    pub count: u64,
+    pub p50: i64,
+    pub p90: i64,
+    pub p99: i64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub(crate) fn record(&mut self, duration: i64) {
+        let value = duration.max(0) as u64;
+        let index = bucket_index(value);
+        if self.counts.len() <= index {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        }
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub(crate) fn percentiles(&self) -> Percentiles {
+        Percentiles {
+            count: self.count,
+            p50: self.quantile(0.5),
+            p90: self.quantile(0.9),
+            p99: self.quantile(0.99),
+        }
+    }
+
+    fn quantile(&self, q: f64) -> i64 {
+        let rank = ((q * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return bucket_middle(index).clamp(self.min, self.max) as i64;
+            }
+        }
+        self.max as i64
+    }
+}
+
+pub(crate) fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    let exponent = 63 - value.leading_zeros();
+    let shift = exponent - SUB_BUCKET_BITS;
+    let sub_bucket = (value >> shift) & (SUB_BUCKETS - 1);
+    ((shift as u64 + 1) * SUB_BUCKETS + sub_bucket) as usize
+}
+
+pub(crate) fn bucket_middle(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    let sub_bucket = index % SUB_BUCKETS;
+    let start = (SUB_BUCKETS + sub_bucket) << shift;
+    start + (1 << shift) / 2
+}
+
+/// Writes one `name,count,p50,p90,p99` row per name, sorted by name.
+pub(crate) fn write_csv<'a>(
+    path: &str,
+    histograms: impl Iterator<Item = (&'a String, &'a Histogram)>,
+) -> io::Result<()> {
+    let mut rows: Vec<_> = histograms.collect();
+    rows.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "name,count,p50_us,p90_us,p99_us")?;
+    for (name, histogram) in rows {
+        let p = histogram.percentiles();
+        writeln!(
+            out,
+            "{},{},{},{},{}",
+            csv_field(name),
+            p.count,
+            p.p50,
+            p.p90,
+            p.p99
+        )?;
+    }
+    out.flush()
+}
+
+pub(crate) fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
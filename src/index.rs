@@ -0,0 +1,173 @@
+//! Sidecar index for seeking into large JSON traces.
+//!
+//! The index is a text file next to the trace with one `<micros> <offset>`
+//! line per time bucket that saw an event end: the start of the bucket and
+//! the byte offset of the first event written in it. Spans are written as
+//! they end and other events as they happen, so ends only grow through the
+//! file, and every event before an offset ended before its bucket started.
+//!
+//! Reading stops early too. An event is written once the latest end seen so
+//! far, the session's clock, has passed its start by at most the index's
+//! `lag`, which is the most that ever took. Once the clock passed the end of
+//! a window by more than that, nothing written later can start inside it.
+//! The index ends with a `lag <micros>` line, without it a window is read to
+//! the end of the trace.
+//!
+//! Shards merged at the end of a session aren't ordered that way. A
+//! `tail <offset>` line marks where they begin, and everything from there on
+//! is always read.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+use crate::reader::{self, TraceReader};
+
+pub(crate) struct TraceIndex {
+    out: BufWriter<File>,
+    bucket: i64,
+    last_bucket: Option<i64>,
+    /// The latest end recorded so far.
+    clock: i64,
+    /// The most `clock` was past the start of an event when it was recorded.
+    lag: i64,
+}
+
+impl TraceIndex {
+    pub(crate) fn create(path: &str, bucket: i64) -> io::Result<Self> {
+        Ok(TraceIndex {
+            out: BufWriter::new(File::create(path)?),
+            bucket: bucket.max(1),
+            last_bucket: None,
+            clock: i64::MIN,
+            lag: 0,
+        })
+    }
+
+    /// Records an event from `start` to `end` about to be written at
+    /// `offset`.
+    pub(crate) fn record(&mut self, start: i64, end: i64, offset: u64) -> io::Result<()> {
+        self.clock = self.clock.max(end);
+        self.lag = self.lag.max(self.clock.saturating_sub(start));
+        let bucket = end.div_euclid(self.bucket);
+        if self.last_bucket.is_some_and(|last| bucket <= last) {
+            return Ok(());
+        }
+        self.last_bucket = Some(bucket);
+        writeln!(self.out, "{} {}", bucket * self.bucket, offset)
+    }
+
+    /// Records that the events from `offset` on aren't in the order of their
+    /// ends.
+    pub(crate) fn record_tail(&mut self, offset: u64) -> io::Result<()> {
+        writeln!(self.out, "tail {offset}")
+    }
+
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        writeln!(self.out, "lag {}", self.lag)?;
+        self.out.flush()
+    }
+}
+
+pub(crate) fn index_path(trace_path: &str) -> String {
+    format!("{trace_path}.idx")
+}
+
+/// The raw JSON of every event of the trace at `trace_path` overlapping
+/// `[t0, t1]`, found through its index.
+pub(crate) fn read_window(trace_path: &str, t0: i64, t1: i64) -> io::Result<Vec<String>> {
+    let index = BufReader::new(File::open(index_path(trace_path))?);
+    let mut offset = None;
+    let mut tail = None;
+    let mut lag = None;
+    for line in index.lines() {
+        let line = line?;
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed index line");
+        let (key, value) = line.split_once(' ').ok_or_else(malformed)?;
+        match key {
+            "tail" => tail = Some(value.parse::<u64>().map_err(|_| malformed())?),
+            "lag" => lag = Some(value.parse::<i64>().map_err(|_| malformed())?),
+            start => {
+                let start = start.parse::<i64>().map_err(|_| malformed())?;
+                let at = value.parse::<u64>().map_err(|_| malformed())?;
+                if start <= t0 && tail.is_none() {
+                    offset = Some(at);
+                }
+            }
+        }
+    }
+
+    let mut trace = File::open(trace_path)?;
+    let mut window = Vec::new();
+    let Some(tail) = tail else {
+        // Written by a session that didn't end, so read to the end.
+        let events = match offset {
+            Some(offset) => {
+                trace.seek(SeekFrom::Start(offset))?;
+                TraceReader::at_event(BufReader::new(trace))
+            }
+            None => TraceReader::new(BufReader::new(trace))?,
+        };
+        read_events(events, t0, t1, None, &mut window)?;
+        return Ok(window);
+    };
+
+    // The ordered events, up to the tail, then the tail in full.
+    let ordered = match offset {
+        Some(offset) => {
+            trace.seek(SeekFrom::Start(offset))?;
+            let ordered = (&mut trace)
+                .take(tail.saturating_sub(offset))
+                .chain(&b"]"[..]);
+            TraceReader::at_event(BufReader::new(ordered))
+        }
+        None => {
+            let ordered = (&mut trace).take(tail).chain(&b"]"[..]);
+            TraceReader::new(BufReader::new(ordered))?
+        }
+    };
+    read_events(
+        ordered,
+        t0,
+        t1,
+        lag.map(|lag| t1.saturating_add(lag)),
+        &mut window,
+    )?;
+    trace.seek(SeekFrom::Start(tail))?;
+    read_events(
+        TraceReader::at_event(BufReader::new(trace)),
+        t0,
+        t1,
+        None,
+        &mut window,
+    )?;
+    Ok(window)
+}
+
+/// Adds the events of `events` overlapping `[t0, t1]` to `window`, stopping
+/// once the latest end read passes `cutoff`.
+fn read_events(
+    mut events: TraceReader<impl BufRead>,
+    t0: i64,
+    t1: i64,
+    cutoff: Option<i64>,
+    window: &mut Vec<String>,
+) -> io::Result<()> {
+    let mut clock = f64::MIN;
+    while let Some(event) = events.next_event()? {
+        let number = |key| reader::field(&event, key).and_then(|v| v.parse::<f64>().ok());
+        let Some(ts) = number("ts") else {
+            continue;
+        };
+        let end = ts + number("dur").unwrap_or(0.0);
+        clock = clock.max(end);
+        if ts <= t1 as f64 && end >= t0 as f64 {
+            window.push(event);
+        }
+        if cutoff.is_some_and(|cutoff| clock > cutoff as f64) {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,3125 @@
+use std::hash::{Hash, Hasher};
+use std::{
+    any::Any,
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, Write},
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock, PoisonError, RwLock, TryLockError,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "track-alloc")]
+mod alloc;
+mod analysis;
+mod binary;
+#[cfg(all(feature = "etw", windows))]
+mod etw;
+mod export;
+mod flusher;
+#[cfg(all(feature = "ftrace", target_os = "linux"))]
+mod ftrace;
+mod histogram;
+mod index;
+mod local;
+#[cfg(feature = "perfetto")]
+mod perfetto;
+mod queue;
+mod reader;
+mod rewrite;
+mod sampling;
+#[cfg(all(feature = "sched-info", target_os = "linux"))]
+mod sched;
+pub mod schema;
+mod shard;
+#[cfg(all(feature = "signpost", target_os = "macos"))]
+mod signpost;
+mod sink;
+#[cfg(test)]
+mod tests;
+mod trace;
+
+#[cfg(feature = "track-alloc")]
+pub use alloc::TracingAllocator;
+pub use histogram::Percentiles;
+pub use queue::OverflowPolicy;
+pub use reader::ParseError;
+pub use sink::{JsonSink, SinkError};
+pub use trace::Trace;
+
+/// Version of the layout of the files this crate writes, recorded in the
+/// header of every JSON trace. Bumped whenever a reader would need to tell
+/// old files from new ones.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Idle gaps shorter than this aren't worth a marker.
+const IDLE_THRESHOLD_MICROS: i64 = 100;
+
+/// Span durations above this, a year, are almost certainly a clock bug
+/// rather than a span that really ran that long.
+const IMPLAUSIBLE_MICROS: i64 = 365 * 24 * 60 * 60 * 1_000_000;
+
+/// Converts `duration` to whole microseconds, saturating at `i64::MAX`
+/// instead of wrapping to a negative value the viewer can't display.
+fn duration_to_micros(duration: Duration) -> i64 {
+    i64::try_from(duration.as_micros()).unwrap_or(i64::MAX)
+}
+
+/// The `(start, end)` relative to `session_start` of a span that began at
+/// `start` and lasted `duration` microseconds. A span that began before the
+/// session is cut off at the session's start, keeping its end.
+fn session_span(session_start: Instant, start: Instant, duration: i64) -> (i64, i64) {
+    let before = duration_to_micros(session_start.saturating_duration_since(start));
+    let start = duration_to_micros(start.saturating_duration_since(session_start));
+    (
+        start,
+        start.saturating_add(duration.saturating_sub(before).max(0)),
+    )
+}
+
+/// A stable 32 bit id for the calling thread, used as the `tid` of its events.
+pub(crate) fn current_thread_id() -> u32 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    std::thread::current().id().hash(&mut hasher);
+    hasher.finish() as u32
+}
+
+/// The `tid` timers on this thread record their spans under: the track set
+/// with [`Instrumentor::set_current_track`], or the thread's own id.
+fn current_track_id() -> u32 {
+    CURRENT_TRACK.get().unwrap_or_else(current_thread_id)
+}
+
+/// The name of the calling thread's row: the name it was spawned with if it
+/// has one, unless `thread_id` is a track set by the user.
+fn current_thread_name(thread_id: u32) -> String {
+    let thread = std::thread::current();
+    match (CURRENT_TRACK.get(), thread.name()) {
+        (None, Some(name)) => name.to_string(),
+        _ => thread_id.to_string(),
+    }
+}
+
+/// Escapes `s` for use inside a JSON string literal.
+fn escape_json(s: &str) -> Cow<'_, str> {
+    if !s.chars().any(|c| matches!(c, '"' | '\\') || c.is_control()) {
+        return Cow::Borrowed(s);
+    }
+    let mut escaped = String::with_capacity(s.len() + 2);
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Output format of a session's trace file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceFormat {
+    /// The JSON object format understood by chrome://tracing.
+    #[default]
+    ChromeTracing,
+    /// The same JSON events, with the `displayTimeUnit`, `systemTraceEvents`
+    /// and `metadata` keys ui.perfetto.dev looks for. Lighter than
+    /// `TraceFormat::PerfettoProto`, and still loads in chrome://tracing.
+    PerfettoJson,
+    /// Perfetto's native protobuf `Trace` format, for ui.perfetto.dev. A
+    /// thread's nested spans are written along with the top-level span they
+    /// are in, once that ends.
+    #[cfg(feature = "perfetto")]
+    PerfettoProto,
+    /// Fixed-size binary records and a string table, the cheapest to write
+    /// and the smallest on disk. Viewers can't load it, convert it with
+    /// [`Instrumentor::binary_to_chrome`] first.
+    Binary,
+    /// No events at all, only a `name,count,total_us` CSV row per span name
+    /// written when the session ends, sorted by total time. For coarse
+    /// profiling where the timeline isn't needed, see also
+    /// [`Instrumentor::accumulate`].
+    Totals,
+}
+
+impl TraceFormat {
+    fn is_json(self) -> bool {
+        matches!(self, TraceFormat::ChromeTracing | TraceFormat::PerfettoJson)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ProfileResult {
+    pub name: String,
+    pub start: i64,
+    pub end: i64,
+    pub thread_id: u32,
+    /// Number of enclosing spans still open on the thread when this one
+    /// started, 0 for top-level spans.
+    pub depth: u32,
+    /// Extra key/value pairs shown with the event, written as its `args`.
+    pub args: Vec<(String, ArgValue)>,
+    /// Free-form labels like `"io"` or `"hot"`, written as a `tags` array
+    /// among the args. See [`Instrumentor::summary_by_tag`].
+    pub tags: Vec<&'static str>,
+}
+
+/// The value of an arg, written as the matching JSON type so viewers can
+/// sort and plot numbers.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(untagged))]
+pub enum ArgValue {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl ArgValue {
+    fn write_json(&self, stream: &mut impl Write) -> io::Result<()> {
+        match self {
+            ArgValue::Str(value) => write!(stream, "\"{}\"", escape_json(value)),
+            ArgValue::Int(value) => write!(stream, "{}", value),
+            // JSON has no NaN or infinities.
+            ArgValue::Float(value) if !value.is_finite() => write!(stream, "\"{}\"", value),
+            ArgValue::Float(value) => write!(stream, "{}", value),
+            ArgValue::Bool(value) => write!(stream, "{}", value),
+        }
+    }
+}
+
+impl From<&str> for ArgValue {
+    fn from(value: &str) -> Self {
+        ArgValue::Str(value.to_string())
+    }
+}
+
+impl From<String> for ArgValue {
+    fn from(value: String) -> Self {
+        ArgValue::Str(value)
+    }
+}
+
+impl From<bool> for ArgValue {
+    fn from(value: bool) -> Self {
+        ArgValue::Bool(value)
+    }
+}
+
+macro_rules! arg_value_from {
+    ($variant:ident, $as:ty, $($from:ty),*) => {
+        $(
+            impl From<$from> for ArgValue {
+                fn from(value: $from) -> Self {
+                    ArgValue::$variant(value as $as)
+                }
+            }
+        )*
+    };
+}
+
+arg_value_from!(Int, i64, i8, i16, i32, i64, u8, u16, u32);
+
+/// Like [`arg_value_from!`], for integers that may not fit into an `i64`,
+/// saturating at its bounds.
+macro_rules! arg_value_from_saturating {
+    ($($from:ty),*) => {
+        $(
+            impl From<$from> for ArgValue {
+                fn from(value: $from) -> Self {
+                    let saturated = if value < 0 as $from { i64::MIN } else { i64::MAX };
+                    ArgValue::Int(i64::try_from(value).unwrap_or(saturated))
+                }
+            }
+        )*
+    };
+}
+
+arg_value_from_saturating!(u64, usize, isize, i128, u128);
+arg_value_from!(Float, f64, f32, f64);
+
+/// Any JSON value, for events built field by field with
+/// [`Instrumentor::write_raw_event`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Value(ArgValue),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    fn write_json(&self, stream: &mut impl Write) -> io::Result<()> {
+        match self {
+            JsonValue::Null => write!(stream, "null"),
+            JsonValue::Value(value) => value.write_json(stream),
+            JsonValue::Array(values) => {
+                write!(stream, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(stream, ",")?;
+                    }
+                    value.write_json(stream)?;
+                }
+                write!(stream, "]")
+            }
+            JsonValue::Object(fields) => write_json_object(stream, fields),
+        }
+    }
+}
+
+impl<T: Into<ArgValue>> From<T> for JsonValue {
+    fn from(value: T) -> Self {
+        JsonValue::Value(value.into())
+    }
+}
+
+fn write_json_object<K: AsRef<str>>(
+    stream: &mut impl Write,
+    fields: &[(K, JsonValue)],
+) -> io::Result<()> {
+    write!(stream, "{{")?;
+    for (i, (key, value)) in fields.iter().enumerate() {
+        if i > 0 {
+            write!(stream, ",")?;
+        }
+        write!(stream, "\"{}\":", escape_json(key.as_ref()))?;
+        value.write_json(stream)?;
+    }
+    write!(stream, "}}")
+}
+
+impl ProfileResult {
+    /// Exact number of bytes writing this span adds to the current session's
+    /// trace if it's in `format`, normally the session's own. The span is
+    /// counted as the session would write it, with its name filtered and
+    /// args like `wall` and `seq` added, and so is the separating comma,
+    /// indentation, an idle event written before it, a thread descriptor or
+    /// a string table entry. 0 if no session is writing or it leaves the
+    /// span out.
+    ///
+    /// Nested spans of a [`TraceFormat::PerfettoProto`] session are written
+    /// once their top-level span ends. This locks the instrumentor, so it
+    /// mustn't be called from a [`SessionObserver`] or the name filter.
+    pub fn encoded_len(&self, format: TraceFormat) -> usize {
+        lock_instrumentor().event_len(self, format)
+    }
+
+    /// `end - start`, saturating for spans no clock could have measured.
+    fn duration(&self) -> i64 {
+        self.end.saturating_sub(self.start)
+    }
+
+    fn write_chrome_json(&self, stream: &mut impl Write, category: &str) -> io::Result<()> {
+        write!(stream, "{{")?;
+        if !self.args.is_empty() || !self.tags.is_empty() {
+            write!(stream, "\"args\":{{")?;
+            for (i, (key, value)) in self.args.iter().enumerate() {
+                if i > 0 {
+                    write!(stream, ",")?;
+                }
+                write!(stream, "\"{}\":", escape_json(key))?;
+                value.write_json(stream)?;
+            }
+            if !self.tags.is_empty() {
+                if !self.args.is_empty() {
+                    write!(stream, ",")?;
+                }
+                write!(stream, "\"tags\":[")?;
+                for (i, tag) in self.tags.iter().enumerate() {
+                    if i > 0 {
+                        write!(stream, ",")?;
+                    }
+                    write!(stream, "\"{}\"", escape_json(tag))?;
+                }
+                write!(stream, "]")?;
+            }
+            write!(stream, "}},")?;
+        }
+        // The fields are listed in `schema`, like those of the other events.
+        write!(stream, "\"cat\":\"{}\",\"dur\":{},\"name\":\"{}\",\"ph\":\"X\",\"pid\":0,\"tid\":{},\"ts\":{}}}",
+            category,
+            self.duration(),
+            escape_json(&self.name),
+            self.thread_id,
+            self.start,
+        )
+    }
+}
+
+/// Writes the JSON header, with the clock sync snapshot if the wall clock
+/// time of `ts` 0 is known, and the machine's description if `environment`.
+fn write_chrome_header(
+    stream: &mut impl Write,
+    schema: u32,
+    unix_micros: Option<i64>,
+    environment: bool,
+) -> io::Result<()> {
+    write!(
+        stream,
+        "{{\"otherData\": {{\"simpleTracingVersion\":\"{}\",\"schema\":{}",
+        env!("CARGO_PKG_VERSION"),
+        schema,
+    )?;
+    if let Some(unix_micros) = unix_micros {
+        write!(stream, ",")?;
+        write_clock_sync(stream, unix_micros)?;
+    }
+    if environment {
+        write!(stream, ",")?;
+        write_environment(stream)?;
+    }
+    write!(stream, "}},\"traceEvents\":[")
+}
+
+/// The `environment` header field, see [`Instrumentor::set_emit_environment`].
+fn write_environment(stream: &mut impl Write) -> io::Result<()> {
+    write!(
+        stream,
+        "\"environment\":{{\"arch\":\"{}\",",
+        std::env::consts::ARCH
+    )?;
+    if let Ok(cpus) = std::thread::available_parallelism() {
+        write!(stream, "\"cpus\":{},", cpus)?;
+    }
+    write!(stream, "\"os\":\"{}\"", std::env::consts::OS)?;
+    // Only Linux lists a process' threads where they can be counted.
+    if let Ok(threads) = std::fs::read_dir("/proc/self/task") {
+        write!(stream, ",\"threads\":{}", threads.count())?;
+    }
+    write!(stream, "}}")
+}
+
+/// When the process started, or `None` where that isn't known. On Linux it's
+/// the process' `starttime` in `/proc/self/stat` set against the system's
+/// `/proc/uptime`, both kept in hundredths of a second, read once.
+fn process_start() -> Option<Instant> {
+    static PROCESS_START: OnceLock<Option<Instant>> = OnceLock::new();
+    *PROCESS_START.get_or_init(|| {
+        #[cfg(target_os = "linux")]
+        {
+            extern "C" {
+                fn sysconf(name: std::ffi::c_int) -> std::ffi::c_long;
+            }
+            const SC_CLK_TCK: std::ffi::c_int = 2;
+
+            let uptime: f64 = std::fs::read_to_string("/proc/uptime")
+                .ok()?
+                .split_whitespace()
+                .next()?
+                .parse()
+                .ok()?;
+            // `starttime` is the 22nd field, the 20th after the command name
+            // in parentheses, which can contain spaces.
+            let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+            let start_ticks: u64 = stat[stat.rfind(')')? + 1..]
+                .split_whitespace()
+                .nth(19)?
+                .parse()
+                .ok()?;
+            // SAFETY: sysconf has no preconditions.
+            let ticks_per_second = unsafe { sysconf(SC_CLK_TCK) };
+            if ticks_per_second <= 0 {
+                return None;
+            }
+            let age = uptime - start_ticks as f64 / ticks_per_second as f64;
+            Instant::now().checked_sub(Duration::from_secs_f64(age.max(0.0)))
+        }
+        #[cfg(not(target_os = "linux"))]
+        None
+    })
+}
+
+/// The `clockSync` header field, tying `ts` 0 to wall clock time so traces
+/// from different hosts can be aligned, see [`Instrumentor::merge_traces`].
+fn write_clock_sync(stream: &mut impl Write, unix_micros: i64) -> io::Result<()> {
+    write!(
+        stream,
+        "\"clockSync\":{{\"ts\":0,\"unixMicros\":{}}}",
+        unix_micros
+    )
+}
+
+/// The `thread_name` metadata event, `args` being written after the name.
+fn write_thread_name_json(
+    stream: &mut impl Write,
+    thread_id: u32,
+    name: &str,
+    args: &[(String, ArgValue)],
+) -> io::Result<()> {
+    write!(stream, "{{\"args\":{{\"name\":\"{}\"", escape_json(name))?;
+    for (key, value) in args {
+        write!(stream, ",\"{}\":", escape_json(key))?;
+        value.write_json(stream)?;
+    }
+    write!(
+        stream,
+        "}},\"cat\":\"__metadata\",\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\"tid\":{}}}",
+        thread_id,
+    )
+}
+
+/// Extra args for the calling thread's `thread_name` event: its `nice` value
+/// and CPU `affinity` with the `sched-info` feature on Linux, nothing
+/// otherwise.
+fn current_thread_metadata() -> Vec<(String, ArgValue)> {
+    #[cfg(all(feature = "sched-info", target_os = "linux"))]
+    return sched::thread_args();
+    #[cfg(not(all(feature = "sched-info", target_os = "linux")))]
+    Vec::new()
+}
+
+/// A writer that only counts the bytes written to it.
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Aggregated durations of every span sharing a name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NameStats {
+    pub count: u64,
+    pub total: i64,
+    pub min: i64,
+    pub max: i64,
+}
+
+impl NameStats {
+    /// Mean span duration in microseconds, or 0 if nothing was recorded.
+    pub fn mean(&self) -> i64 {
+        if self.count == 0 {
+            0
+        } else {
+            self.total / self.count as i64
+        }
+    }
+
+    fn record(&mut self, duration: i64) {
+        self.merge(&NameStats {
+            count: 1,
+            total: duration,
+            min: duration,
+            max: duration,
+        });
+    }
+
+    fn merge(&mut self, other: &NameStats) {
+        if self.count == 0 {
+            *self = *other;
+            return;
+        }
+        self.count += other.count;
+        self.total = self.total.saturating_add(other.total);
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+}
+
+/// How the spans of one name changed between two traces, see
+/// [`Instrumentor::diff_traces`]. Durations are in microseconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameDiff {
+    pub name: String,
+    /// All zero if the name only appears in the candidate.
+    pub baseline: NameStats,
+    /// All zero if the name only appears in the baseline.
+    pub candidate: NameStats,
+    /// Candidate total minus baseline total, negative if it got faster.
+    pub total_delta: i64,
+    pub mean_delta: i64,
+    /// `total_delta` in percent of the baseline total, `None` if that's 0.
+    pub total_change: Option<f64>,
+}
+
+/// Ids tying this thread's spans to a distributed trace, e.g. OpenTelemetry
+/// trace and span ids. See [`Instrumentor::set_correlation_id`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorrelationId {
+    pub trace_id: String,
+    pub span_id: Option<String>,
+}
+
+/// Identifies the operation an async event belongs to, see
+/// [`Instrumentor::async_begin`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AsyncId {
+    /// The legacy `id` field, fine as long as a trace holds a single process.
+    Id(u64),
+    /// `id2` scoped to this process, so it can't collide with the same id in
+    /// another process once traces are merged.
+    Local(u64),
+    /// `id2` shared by every process, for operations spanning several.
+    Global(u64),
+}
+
+impl AsyncId {
+    fn write_json(&self, stream: &mut impl Write) -> io::Result<()> {
+        match self {
+            AsyncId::Id(id) => write!(stream, "\"id\":{}", id),
+            AsyncId::Local(id) => write!(stream, "\"id2\":{{\"local\":{}}}", id),
+            AsyncId::Global(id) => write!(stream, "\"id2\":{{\"global\":{}}}", id),
+        }
+    }
+}
+
+/// Rewrites span names before they're written, see
+/// [`Instrumentor::set_name_filter`].
+pub type NameFilter = Box<dyn Fn(&str) -> Cow<'_, str> + Send + Sync>;
+
+/// Follows sessions as they're recorded, e.g. to forward spans to a live
+/// view that has to start over with each session. See
+/// [`Instrumentor::add_observer`], and [`JsonSink`] for one writing a trace
+/// of its own.
+///
+/// The methods are called with the instrumentor locked, so they must not
+/// call back into it, and should return quickly since every timer waits on
+/// them.
+pub trait SessionObserver: Send {
+    /// A session named `name` began and its header was written.
+    fn on_begin(&mut self, name: &str) {
+        let _ = name;
+    }
+
+    /// A span was recorded, after the name filter and the other checks.
+    fn on_event(&mut self, result: &ProfileResult) {
+        let _ = result;
+    }
+
+    /// The current session ended and its output was closed.
+    fn on_end(&mut self) {}
+}
+
+/// Decides by name which spans get recorded, see
+/// [`Instrumentor::set_record_predicate`].
+pub type RecordPredicate = Box<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// The spans of a possibly truncated trace, see
+/// [`Instrumentor::parse_truncated_session_bytes`].
+#[derive(Debug, Clone)]
+pub struct RecoveredSession {
+    pub spans: Vec<ProfileResult>,
+    /// Whether the trace ended before its footer.
+    pub truncated: bool,
+}
+
+/// What a finished session wrote, see [`Instrumentor::last_session_stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionStats {
+    pub name: String,
+    /// Events written, including metadata like thread names.
+    pub event_count: usize,
+    pub duration: Duration,
+    /// Size of the trace, 0 if the output isn't a regular file.
+    pub bytes: u64,
+}
+
+/// A span begun with [`Instrumentor::begin_span`] or
+/// [`Instrumentor::begin_child`], written as its `id` arg.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpanId(u64);
+
+/// A manually begun span waiting for its end.
+struct OpenSpan {
+    name: String,
+    start: Instant,
+    thread_id: u32,
+    depth: u32,
+    parent: Option<SpanId>,
+}
+
+static NEXT_SPAN_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug)]
+pub struct InstrumentationSession {
+    pub name: String,
+    /// Where the trace is written, `None` for sessions on an inherited file
+    /// descriptor or handle.
+    pub filepath: Option<String>,
+    pub format: TraceFormat,
+    pub pretty: bool,
+    pub start: Instant,
+}
+
+pub struct Instrumentor {
+    current_session: Option<InstrumentationSession>,
+    output_stream: Option<OutputStream>,
+    /// Length of the output when it was closed.
+    output_len: u64,
+    /// Events written by the session. Atomic so that writers not holding
+    /// the instrumentor's lock can count theirs too.
+    profile_count: AtomicUsize,
+    last_session: Option<SessionStats>,
+    late_events: usize,
+    last_error: Option<io::Error>,
+    /// The writer of the last session begun with
+    /// [`Instrumentor::begin_session_with_writer`] once it ended, with the
+    /// session's id.
+    finished_writer: Option<(u64, Box<dyn Any + Send>)>,
+    /// Id of the current or last session, counting sessions begun so far.
+    /// Lets a [`SessionGuard`] tell its session from a later one.
+    session_id: u64,
+    summary: HashMap<(u32, String), NameStats>,
+    tag_summary: HashMap<(&'static str, String), NameStats>,
+    histograms: HashMap<String, histogram::Histogram>,
+    counters: HashMap<String, f64>,
+    retain_events: bool,
+    events: Vec<ProfileResult>,
+    last_event: Option<ProfileResult>,
+    last_end: HashMap<u32, i64>,
+    open_spans: HashMap<SpanId, OpenSpan>,
+    named_threads: HashSet<u32>,
+    emit_idle: bool,
+    observers: Vec<Box<dyn SessionObserver>>,
+    record_predicate: Option<RecordPredicate>,
+    /// Session-relative `[start, end]` spans have to start in to be recorded,
+    /// in microseconds.
+    record_window: Option<(i64, i64)>,
+    sampling_interval: Option<Duration>,
+    sampler: Option<sampling::Sampler>,
+    flush_every: usize,
+    flush_interval: Option<Duration>,
+    flusher: Option<flusher::Flusher>,
+    async_capacity: Option<usize>,
+    overflow_policy: OverflowPolicy,
+    writer: Option<queue::Writer>,
+    index_bucket: Option<Duration>,
+    index: Option<index::TraceIndex>,
+    /// `(parent id, name)` of every stack frame sampled this session. A
+    /// frame's id is its index plus one, 0 means no parent.
+    stack_frames: Vec<(u32, String)>,
+    stack_frame_ids: HashMap<(u32, String), u32>,
+    format: TraceFormat,
+    pretty: bool,
+    fail_if_exists: bool,
+    emit_session_markers: bool,
+    emit_environment: bool,
+    record_walltime: bool,
+    stamp_sequence: bool,
+    /// Wall clock time of the current session's start, in microseconds since
+    /// the Unix epoch.
+    start_unix_micros: i64,
+    sharded: bool,
+    sort_shards: bool,
+    reset_on_end: bool,
+    #[cfg(feature = "perfetto")]
+    perfetto: perfetto::PerfettoState,
+    binary: binary::BinaryState,
+}
+
+lazy_static::lazy_static! {
+    static ref INSTRUMENTOR: Arc<Mutex<Instrumentor>> = Arc::new(Mutex::new(Instrumentor::new()));
+}
+
+/// Locks the instrumentor, also after a user callback panicked while it was
+/// locked: timers stop in `Drop`, where panicking on the poisoned lock would
+/// abort the process while it unwinds.
+fn lock_instrumentor() -> MutexGuard<'static, Instrumentor> {
+    INSTRUMENTOR.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Runs a user callback called with the instrumentor locked. Its panic is
+/// passed on, except while the thread is already unwinding, e.g. through a
+/// timer's `Drop`, where it would abort the process: then `None` is returned
+/// and the callback is skipped.
+fn run_callback<R>(callback: impl FnOnce() -> R) -> Option<R> {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(callback)) {
+        Ok(result) => Some(result),
+        Err(_) if std::thread::panicking() => None,
+        Err(payload) => std::panic::resume_unwind(payload),
+    }
+}
+
+impl Instrumentor {
+    fn new() -> Self {
+        Instrumentor {
+            current_session: None,
+            output_stream: None,
+            output_len: 0,
+            profile_count: AtomicUsize::new(0),
+            last_session: None,
+            late_events: 0,
+            last_error: None,
+            finished_writer: None,
+            session_id: 0,
+            summary: HashMap::new(),
+            tag_summary: HashMap::new(),
+            histograms: HashMap::new(),
+            counters: HashMap::new(),
+            retain_events: false,
+            events: Vec::new(),
+            last_event: None,
+            last_end: HashMap::new(),
+            open_spans: HashMap::new(),
+            named_threads: HashSet::new(),
+            emit_idle: false,
+            observers: Vec::new(),
+            record_predicate: None,
+            record_window: None,
+            sampling_interval: None,
+            sampler: None,
+            flush_every: 1,
+            flush_interval: None,
+            flusher: None,
+            async_capacity: None,
+            overflow_policy: OverflowPolicy::default(),
+            writer: None,
+            index_bucket: None,
+            index: None,
+            stack_frames: Vec::new(),
+            stack_frame_ids: HashMap::new(),
+            format: TraceFormat::default(),
+            pretty: false,
+            fail_if_exists: false,
+            emit_session_markers: false,
+            emit_environment: false,
+            record_walltime: false,
+            stamp_sequence: false,
+            start_unix_micros: 0,
+            sharded: false,
+            sort_shards: false,
+            reset_on_end: false,
+            #[cfg(feature = "perfetto")]
+            perfetto: perfetto::PerfettoState::default(),
+            binary: binary::BinaryState::default(),
+        }
+    }
+
+    /// Sets the output format used by sessions started after this call.
+    pub fn set_format(format: TraceFormat) {
+        lock_instrumentor().format = format;
+    }
+
+    /// Puts each event of sessions started after this call on its own
+    /// indented line, which is easier to read but larger. Only affects JSON
+    /// output.
+    pub fn set_pretty(pretty: bool) {
+        lock_instrumentor().pretty = pretty;
+    }
+
+    /// Makes sessions begun with a path fail instead of truncating a file that
+    /// already exists, e.g. the unfinished trace of another run. Off by
+    /// default. Use [`Instrumentor::try_begin_session`] to see the error.
+    pub fn set_fail_if_exists(fail_if_exists: bool) {
+        lock_instrumentor().fail_if_exists = fail_if_exists;
+    }
+
+    /// Writes `SessionBegin` and `SessionEnd` instant events at the start and
+    /// end of JSON sessions, with the process' uptime as `uptimeMicros`. That
+    /// places short sessions of a long running process in its lifetime. The
+    /// process start is only known on Linux, to 10ms, elsewhere the events
+    /// have no args.
+    pub fn set_emit_session_markers(emit: bool) {
+        lock_instrumentor().emit_session_markers = emit;
+    }
+
+    /// Describes the machine in the header of JSON sessions begun from now
+    /// on, as an `environment` object holding `arch`, `os`, the number of
+    /// `cpus` available to the process and, on Linux, the number of
+    /// `threads` it runs when the session begins, so a shared trace tells
+    /// where it was recorded. Shown in the metadata view of chrome://tracing.
+    pub fn set_emit_environment(emit: bool) {
+        lock_instrumentor().emit_environment = emit;
+    }
+
+    /// Adds each span's start as wall clock time, in microseconds since the
+    /// Unix epoch, as a `wall` arg, e.g. to match spans with log lines. `ts`
+    /// stays relative to the session start. It's the session's start time
+    /// plus `ts`, so it doesn't read the system clock for every span.
+    pub fn set_record_walltime(record: bool) {
+        lock_instrumentor().record_walltime = record;
+    }
+
+    /// Adds each span's position among the session's events as a `seq` arg,
+    /// counting from 0 like [`Instrumentor::event_count`], e.g. to refer to
+    /// one event or check the order of merged traces.
+    pub fn set_stamp_sequence(stamp: bool) {
+        lock_instrumentor().stamp_sequence = stamp;
+    }
+
+    /// Makes JSON sessions begun from now on record the spans of each thread
+    /// into a file of its own, merged into the trace when the session ends.
+    /// Timers then never wait on each other, for write-heavy multithreaded
+    /// programs.
+    ///
+    /// Spans recorded by timers in a sharded session skip the record
+    /// predicate and window, observers, the summary and the other
+    /// per-session bookkeeping, which would need a shared lock. Their names
+    /// are still filtered and cut to [`Instrumentor::set_max_name_len`] before
+    /// they reach the shard, and their args, backtraces and correlation ids
+    /// are kept.
+    pub fn set_sharded(sharded: bool) {
+        lock_instrumentor().sharded = sharded;
+    }
+
+    /// Sorts the spans merged from the shards of a sharded session by start
+    /// time, instead of appending them thread by thread. This holds all of
+    /// them in memory while the session ends.
+    pub fn set_sort_shards(sort: bool) {
+        lock_instrumentor().sort_shards = sort;
+    }
+
+    /// Records the gaps between top-level spans on each thread as `idle`
+    /// events, making time spent outside instrumented code visible.
+    pub fn set_emit_idle(emit_idle: bool) {
+        lock_instrumentor().emit_idle = emit_idle;
+    }
+
+    /// Keeps every span written from now on in memory, so the session can be
+    /// analysed after it ends, e.g. with [`Instrumentor::critical_path`].
+    /// Memory grows with the number of spans, so leave it off for long runs.
+    pub fn set_retain_events(retain_events: bool) {
+        lock_instrumentor().retain_events = retain_events;
+    }
+
+    /// Passes every span name through `filter` before it's written, e.g. to
+    /// redact paths or user ids from traces that get shared. The summaries
+    /// see the rewritten names too. Runs on every event, so keep it cheap and
+    /// return `Cow::Borrowed` when nothing changes.
+    ///
+    /// A panic in `filter` reaches the code stopping the timer, unless that
+    /// thread is already unwinding, in which case the name is left as it is.
+    /// The same goes for record predicates, which then record the span, and
+    /// for observers, which then miss the call.
+    pub fn set_name_filter(filter: NameFilter) {
+        *NAME_FILTER.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(filter));
+    }
+
+    /// Registers `observer` to be told about every session from now on, after
+    /// any observers added before it.
+    pub fn add_observer(observer: Box<dyn SessionObserver>) {
+        lock_instrumentor().observers.push(observer);
+    }
+
+    /// Removes every observer added with [`Instrumentor::add_observer`].
+    pub fn clear_observers() {
+        lock_instrumentor().observers.clear();
+    }
+
+    /// Cuts span names longer than `len` characters down to `len`, ending in
+    /// an ellipsis, e.g. for names holding whole SQL queries. Applies after
+    /// the name filter. 0, the default, keeps names whole.
+    pub fn set_max_name_len(len: usize) {
+        MAX_NAME_LEN.store(len, Ordering::Relaxed);
+    }
+
+    /// Removes the filter set by [`Instrumentor::set_name_filter`].
+    pub fn clear_name_filter() {
+        *NAME_FILTER.write().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    /// Only records the spans `predicate` returns true for, called with the
+    /// span's name before [`Instrumentor::set_name_filter`] rewrites it.
+    /// Everything else is dropped as if it hadn't happened, summaries
+    /// included. To skip the cost of timing too, decide at the call site with
+    /// [`tracing_if!`].
+    pub fn set_record_predicate(predicate: RecordPredicate) {
+        lock_instrumentor().record_predicate = Some(predicate);
+    }
+
+    /// Removes the predicate set by [`Instrumentor::set_record_predicate`].
+    pub fn clear_record_predicate() {
+        lock_instrumentor().record_predicate = None;
+    }
+
+    /// Only records the spans starting between `start` and `end` into a
+    /// session, e.g. to catch a transient issue seconds into a run without
+    /// recording everything around it. Other spans are dropped right away.
+    pub fn set_record_window(start: Duration, end: Duration) {
+        lock_instrumentor().record_window =
+            Some((duration_to_micros(start), duration_to_micros(end)));
+    }
+
+    /// Records whole sessions again after [`Instrumentor::set_record_window`].
+    pub fn clear_record_window() {
+        lock_instrumentor().record_window = None;
+    }
+
+    /// Samples the spans open on every thread once per `interval`, for the
+    /// current session if there is one and all sessions after it. Samples
+    /// show up as a sampled flame graph next to the exact spans. Only JSON
+    /// sessions are sampled.
+    pub fn enable_sampling(interval: Duration) {
+        let mut instrumentor = lock_instrumentor();
+        instrumentor.sampling_interval = Some(interval);
+        if instrumentor.current_session.is_some() && instrumentor.sampler.is_none() {
+            instrumentor.start_sampler();
+        }
+    }
+
+    /// Flushes the output after every `n` events instead of after each one,
+    /// trading how much of the trace a crash can lose for throughput. 0 only
+    /// flushes on [`Instrumentor::set_flush_interval`] and at the end.
+    pub fn set_flush_every(n: usize) {
+        lock_instrumentor().flush_every = n;
+    }
+
+    /// Also flushes the output once per `interval` from a background thread,
+    /// bounding how long a written event can sit in the buffer. `None` stops
+    /// the periodic flushes.
+    pub fn set_flush_interval(interval: Option<Duration>) {
+        let flusher = {
+            let mut instrumentor = lock_instrumentor();
+            instrumentor.flush_interval = interval;
+            instrumentor.flusher.take()
+        };
+        if let Some(flusher) = flusher {
+            flusher.stop();
+        }
+        let mut instrumentor = lock_instrumentor();
+        if instrumentor.current_session.is_some() {
+            instrumentor.start_flusher();
+        }
+    }
+
+    /// Makes timers of sessions begun from now on hand their spans to a
+    /// background thread through a queue of `capacity` spans, instead of
+    /// waiting on the lock to write them. `None` writes them from the timers.
+    ///
+    /// Queued spans aren't covered by [`Instrumentor::flush`], they're all
+    /// written by the time the session ends.
+    pub fn set_async_writing(capacity: Option<usize>) {
+        lock_instrumentor().async_capacity = capacity;
+    }
+
+    /// What timers do when the queue of [`Instrumentor::set_async_writing`]
+    /// is full, for sessions begun from now on. Blocks by default.
+    pub fn set_overflow_policy(policy: OverflowPolicy) {
+        lock_instrumentor().overflow_policy = policy;
+    }
+
+    /// Skips writing spans named like the span they're directly nested in,
+    /// e.g. the calls of a recursive function, so a recursion shows up as
+    /// its outermost span, which covers the time of all the others. Spans
+    /// nested under a skipped one are still written.
+    pub fn set_collapse_recursion(collapse: bool) {
+        COLLAPSE_RECURSION.store(collapse, Ordering::Relaxed);
+    }
+
+    /// Number of spans skipped by [`Instrumentor::set_collapse_recursion`]
+    /// since the current or last session began.
+    pub fn collapsed_count() -> usize {
+        COLLAPSED.load(Ordering::Relaxed)
+    }
+
+    /// Number of spans the current or last session dropped because the queue
+    /// of [`Instrumentor::set_async_writing`] was full.
+    pub fn dropped_count() -> usize {
+        queue::DROPPED.load(Ordering::Relaxed)
+    }
+
+    /// Writes an index next to the JSON trace of every session begun with a
+    /// path from now on, at `<path>.idx`, mapping buckets of `bucket` to byte
+    /// offsets in the trace. See [`Instrumentor::read_trace_window`].
+    pub fn enable_index(bucket: Duration) {
+        lock_instrumentor().index_bucket = Some(bucket);
+    }
+
+    /// Stops writing indexes for sessions begun from now on.
+    pub fn disable_index() {
+        lock_instrumentor().index_bucket = None;
+    }
+
+    /// Stops sampling started by [`Instrumentor::enable_sampling`].
+    pub fn disable_sampling() {
+        let sampler = {
+            let mut instrumentor = lock_instrumentor();
+            instrumentor.sampling_interval = None;
+            instrumentor.sampler.take()
+        };
+        if let Some(sampler) = sampler {
+            sampler.stop();
+        }
+    }
+
+    /// Records the spans of timers stopped on this thread under `track`
+    /// instead of the thread's own id. Runtimes multiplexing many tasks over
+    /// few threads can set this whenever a task is resumed, so every task
+    /// gets its own row in the viewer.
+    pub fn set_current_track(track: u32) {
+        CURRENT_TRACK.set(Some(track));
+    }
+
+    /// Goes back to recording this thread's spans under its own id.
+    pub fn clear_current_track() {
+        CURRENT_TRACK.set(None);
+    }
+
+    /// Adds `id` to the args of every span stopped on this thread from now
+    /// on, as `trace_id` and `span_id`, to line the trace up with the
+    /// distributed trace of the same request. `None` stops adding them.
+    pub fn set_correlation_id(id: Option<CorrelationId>) {
+        CORRELATION_ID.set(id);
+    }
+
+    /// Panics when a timer is stopped while a timer started after it on the
+    /// same thread is still running. Such spans overlap without nesting,
+    /// which the viewer can't display properly. The check is a counter
+    /// comparison, cheap enough to leave on in debug builds.
+    pub fn set_strict_nesting(strict: bool) {
+        STRICT_NESTING.store(strict, Ordering::Relaxed);
+    }
+
+    /// Asserts that only the calling thread records spans, so timers can
+    /// collect their spans on the thread and write them in batches, taking
+    /// the lock once per batch instead of once per span. The batches are
+    /// written when one of them fills, when the session ends or is flushed,
+    /// and when this is turned off.
+    ///
+    /// Breaking the assertion is safe: other threads batch their spans too,
+    /// and every thread's batch is written into the session it was recorded
+    /// in, also once the thread has exited.
+    pub fn set_single_threaded(single_threaded: bool) {
+        SINGLE_THREADED.store(single_threaded, Ordering::Relaxed);
+        if !single_threaded {
+            lock_instrumentor().write_local_spans();
+        }
+    }
+
+    /// Turns timers on and off at runtime, on by default. Timers created
+    /// while off cost one atomic load: they don't read the clock and never
+    /// record anything, even if timers are turned back on before they end.
+    pub fn set_enabled(enabled: bool) {
+        ENABLED.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Attaches the call stack, as a `backtrace` arg, to every span lasting
+    /// longer than `threshold_micros`. Capturing a backtrace is slow, so this
+    /// is for catching rare outliers. `None` turns it off.
+    pub fn set_capture_backtrace_over(threshold_micros: Option<i64>) {
+        BACKTRACE_THRESHOLD.store(threshold_micros.unwrap_or(i64::MAX), Ordering::Relaxed);
+    }
+
+    /// Also writes the begin and end of every span to the kernel's
+    /// `trace_marker`, so spans show up next to scheduling and syscall events
+    /// in `trace-cmd` and `kernelshark`. Independent of sessions. Fails if
+    /// tracefs isn't mounted or `trace_marker` isn't writable.
+    #[cfg(all(feature = "ftrace", target_os = "linux"))]
+    pub fn enable_ftrace() -> io::Result<()> {
+        ftrace::enable()
+    }
+
+    /// Stops writing spans to `trace_marker`. Slices already begun are still
+    /// ended.
+    #[cfg(all(feature = "ftrace", target_os = "linux"))]
+    pub fn disable_ftrace() {
+        ftrace::disable();
+    }
+
+    /// Also writes the begin and end of every span as ETW events of the
+    /// TraceLogging provider `SimpleTracing`, so spans show up next to system
+    /// events in Windows Performance Analyzer. Independent of sessions. The
+    /// events are only written while a trace session enables the provider.
+    /// Does nothing off Windows or without the `etw` feature.
+    pub fn enable_etw() -> io::Result<()> {
+        #[cfg(all(feature = "etw", windows))]
+        {
+            etw::enable()
+        }
+        #[cfg(not(all(feature = "etw", windows)))]
+        {
+            Ok(())
+        }
+    }
+
+    /// Stops writing spans as ETW events and unregisters the provider, once
+    /// the spans already begun have ended.
+    pub fn disable_etw() {
+        #[cfg(all(feature = "etw", windows))]
+        etw::disable();
+    }
+
+    /// Also records every span as an `os_signpost` interval of subsystem
+    /// `simple-tracing`, so spans show up next to system signposts in
+    /// Instruments. Independent of sessions. Does nothing off macOS or
+    /// without the `signpost` feature.
+    pub fn enable_signposts() -> io::Result<()> {
+        #[cfg(all(feature = "signpost", target_os = "macos"))]
+        {
+            signpost::enable()
+        }
+        #[cfg(not(all(feature = "signpost", target_os = "macos")))]
+        {
+            Ok(())
+        }
+    }
+
+    /// Stops recording spans as signposts. Intervals already begun are still
+    /// ended.
+    pub fn disable_signposts() {
+        #[cfg(all(feature = "signpost", target_os = "macos"))]
+        signpost::disable();
+    }
+
+    pub fn begin_session(name: &str, filepath: &str) {
+        let _ = Self::try_begin_session(name, filepath);
+    }
+
+    /// Like [`Instrumentor::begin_session`], but fails if the file can't be
+    /// created, or exists with [`Instrumentor::set_fail_if_exists`] set, or
+    /// another session is already active.
+    pub fn try_begin_session(name: &str, filepath: &str) -> io::Result<()> {
+        let mut instrumentor = lock_instrumentor();
+        instrumentor.internal_begin_session(name, filepath)
+    }
+
+    /// Starts a session writing to the already open file descriptor `fd`,
+    /// e.g. a pipe inherited from a parent process, instead of creating a
+    /// file.
+    ///
+    /// The session writes to a duplicate of `fd`, so ending it only closes
+    /// the duplicate: `fd` stays owned by the caller, who remains responsible
+    /// for closing it.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open file descriptor for the duration of this call.
+    #[cfg(unix)]
+    pub unsafe fn begin_session_from_raw_fd(
+        name: &str,
+        fd: std::os::unix::io::RawFd,
+    ) -> io::Result<()> {
+        let fd = std::os::unix::io::BorrowedFd::borrow_raw(fd).try_clone_to_owned()?;
+        let mut instrumentor = lock_instrumentor();
+        instrumentor.check_no_session()?;
+        instrumentor.start_session(name, None, Output::File(File::from(fd)))
+    }
+
+    /// Starts a session writing to the already open handle `handle`, e.g. a
+    /// pipe inherited from a parent process, instead of creating a file.
+    ///
+    /// The session writes to a duplicate of `handle`, so ending it only
+    /// closes the duplicate: `handle` stays owned by the caller, who remains
+    /// responsible for closing it.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be an open handle for the duration of this call.
+    #[cfg(windows)]
+    pub unsafe fn begin_session_from_raw_handle(
+        name: &str,
+        handle: std::os::windows::io::RawHandle,
+    ) -> io::Result<()> {
+        let handle =
+            std::os::windows::io::BorrowedHandle::borrow_raw(handle).try_clone_to_owned()?;
+        let mut instrumentor = lock_instrumentor();
+        instrumentor.check_no_session()?;
+        instrumentor.start_session(name, None, Output::File(File::from(handle)))
+    }
+
+    /// Starts a session writing to `writer` instead of a file, e.g. a
+    /// `Vec<u8>` capturing the trace in memory.
+    ///
+    /// The session ends when the returned guard drops, or earlier with
+    /// [`Instrumentor::end_session`]. [`SessionGuard::finish`] ends it and
+    /// returns `writer`. Indexes aren't written for these sessions, and
+    /// [`SessionStats::bytes`] is 0.
+    pub fn begin_session_with_writer<W: Write + Send + 'static>(
+        name: &str,
+        writer: W,
+    ) -> io::Result<SessionGuard<W>> {
+        let mut instrumentor = lock_instrumentor();
+        instrumentor.check_no_session()?;
+        instrumentor.finished_writer = None;
+        instrumentor.start_session(name, None, Output::Writer(Box::new(writer)))?;
+        Ok(SessionGuard::new(instrumentor.session_id))
+    }
+
+    /// The path the current session writes to, if it was begun with one.
+    pub fn session_path() -> Option<String> {
+        let instrumentor = lock_instrumentor();
+        instrumentor.current_session.as_ref()?.filepath.clone()
+    }
+
+    pub fn end_session() {
+        Self::end_session_with_id(None);
+    }
+
+    /// Ends the current session, if `id` is given only if that's its id.
+    fn end_session_with_id(id: Option<u64>) {
+        // The sampler, flusher and writer have to be stopped without holding
+        // the lock, they take it to write.
+        let (sampler, flusher, writer) = {
+            let mut instrumentor = lock_instrumentor();
+            if !instrumentor.is_current_session(id) {
+                return;
+            }
+            instrumentor.take_threads()
+        };
+        if let Some(sampler) = sampler {
+            sampler.stop();
+        }
+        if let Some(flusher) = flusher {
+            flusher.stop();
+        }
+        if let Some(writer) = writer {
+            writer.stop();
+        }
+        let mut instrumentor = lock_instrumentor();
+        if instrumentor.is_current_session(id) {
+            instrumentor.internal_end_session();
+        }
+    }
+
+    /// Whether a session is running and has the id `id`, or any id if `None`.
+    fn is_current_session(&self, id: Option<u64>) -> bool {
+        id.is_none_or(|id| self.current_session.is_some() && self.session_id == id)
+    }
+
+    /// Installs a panic hook that ends the current session, writing its
+    /// footer, before running the hook that was installed before it. That
+    /// leaves a loadable trace behind even when the program aborts on panic.
+    ///
+    /// Any panic ends the session, including ones caught later on, except
+    /// those inside [`Instrumentor::profile_catch`], which is known to catch
+    /// them. If the instrumentor is locked when the panic happens, e.g. by
+    /// the panicking thread itself, the trace is left as it is.
+    pub fn install_panic_hook() {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if CATCHING.get() > 0 {
+                previous(info);
+                return;
+            }
+            let locked = match INSTRUMENTOR.try_lock() {
+                Ok(instrumentor) => Some(instrumentor),
+                Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+                Err(TryLockError::WouldBlock) => None,
+            };
+            let (sampler, flusher, writer) = match locked {
+                Some(mut instrumentor) => {
+                    instrumentor.write_queued(queue::close());
+                    instrumentor.internal_end_session();
+                    instrumentor.take_threads()
+                }
+                None => (None, None, None),
+            };
+            if let Some(sampler) = sampler {
+                sampler.stop();
+            }
+            if let Some(flusher) = flusher {
+                flusher.stop();
+            }
+            if let Some(writer) = writer {
+                writer.stop();
+            }
+            previous(info);
+        }));
+    }
+
+    /// Starts timing a span named `name` until the returned [`Span`] is
+    /// dropped, with args and tags that can be added while it runs.
+    pub fn span(name: &str) -> Span<'_> {
+        Span {
+            timer: InstrumentationTimer::new(name),
+        }
+    }
+
+    /// Begins a span that isn't tied to a scope, ended by
+    /// [`Instrumentor::end_span`], possibly on another thread. It's shown on
+    /// the row of the thread that began it.
+    pub fn begin_span(name: &str) -> SpanId {
+        Self::begin_manual_span(name, None)
+    }
+
+    /// Like [`Instrumentor::begin_span`], recording `parent` as the span's
+    /// `parent` arg. This keeps the causal tree of callback driven or cross
+    /// thread work, which lexical nesting can't show.
+    pub fn begin_child(name: &str, parent: SpanId) -> SpanId {
+        Self::begin_manual_span(name, Some(parent))
+    }
+
+    /// Ends and writes a span begun by [`Instrumentor::begin_span`] or
+    /// [`Instrumentor::begin_child`]. Ending it twice does nothing.
+    pub fn end_span(id: SpanId) {
+        let end_timepoint = Instant::now();
+        let mut instrumentor = lock_instrumentor();
+        let Some(span) = instrumentor.open_spans.remove(&id) else {
+            return;
+        };
+        let mut args = vec![("id".to_string(), ArgValue::Int(id.0 as i64))];
+        if let Some(parent) = span.parent {
+            args.push(("parent".to_string(), ArgValue::Int(parent.0 as i64)));
+        }
+        let duration = duration_to_micros(end_timepoint.duration_since(span.start));
+        let (start, end) = instrumentor.session_span(span.start, duration);
+        instrumentor.internal_write_profile(&ProfileResult {
+            name: span.name,
+            start,
+            end,
+            thread_id: span.thread_id,
+            depth: span.depth,
+            args,
+            tags: Vec::new(),
+        });
+    }
+
+    fn begin_manual_span(name: &str, parent: Option<SpanId>) -> SpanId {
+        let id = SpanId(NEXT_SPAN_ID.fetch_add(1, Ordering::Relaxed));
+        let thread_id = current_track_id();
+        let mut instrumentor = lock_instrumentor();
+        instrumentor.name_current_thread(thread_id);
+        instrumentor.open_spans.insert(
+            id,
+            OpenSpan {
+                name: name.to_string(),
+                start: Instant::now(),
+                thread_id,
+                depth: OPEN_TIMERS.get(),
+                parent,
+            },
+        );
+        id
+    }
+
+    /// Begins the async operation `name` identified by `id`, ended by
+    /// [`Instrumentor::async_end`] with the same name and id, possibly on
+    /// another thread. Viewers show operations on their own rows, nested by
+    /// time. JSON sessions only.
+    pub fn async_begin(name: &str, id: AsyncId) {
+        Self::write_async_event(name, id, 'b');
+    }
+
+    /// Ends an async operation begun by [`Instrumentor::async_begin`].
+    pub fn async_end(name: &str, id: AsyncId) {
+        Self::write_async_event(name, id, 'e');
+    }
+
+    fn write_async_event(name: &str, id: AsyncId, phase: char) {
+        let now = Instant::now();
+        let thread_id = current_track_id();
+        let mut instrumentor = lock_instrumentor();
+        if instrumentor.current_session.is_none() {
+            instrumentor.late_events += 1;
+            return;
+        }
+        if !instrumentor.session_format().is_json() {
+            return;
+        }
+
+        instrumentor.name_current_thread(thread_id);
+        let ts = instrumentor.session_micros(now);
+        instrumentor.index_event(ts, ts);
+        let written = instrumentor.write_chrome_event(|stream| {
+            write!(stream, "{{\"cat\":\"async\",")?;
+            id.write_json(stream)?;
+            write!(
+                stream,
+                ",\"name\":\"{}\",\"ph\":\"{}\",\"pid\":0,\"tid\":{},\"ts\":{}}}",
+                escape_json(name),
+                phase,
+                thread_id,
+                ts,
+            )
+        });
+        instrumentor.handle_write(written);
+    }
+
+    /// Writes out every event the current session has buffered. A failure is
+    /// handled like a failed write, see [`Instrumentor::last_error`].
+    ///
+    /// This is the supported way to check a trace while its session is still
+    /// running, e.g. in integration tests: after `flush`, the file holds the
+    /// header and every event recorded so far, and appending `]}` to a JSON
+    /// trace makes it a complete document any JSON parser accepts.
+    ///
+    /// Spans that haven't reached the session yet aren't covered: those in
+    /// the shards of [`Instrumentor::set_sharded`], which are merged when
+    /// the session ends, and those still queued for
+    /// [`Instrumentor::set_async_writing`]. The batches of
+    /// [`Instrumentor::set_single_threaded`] are written.
+    pub fn flush() {
+        let mut instrumentor = lock_instrumentor();
+        instrumentor.write_local_spans();
+        if let Some(ref mut output) = instrumentor.output_stream {
+            let flushed = output.file.get_mut().unwrap().flush();
+            output.unflushed = 0;
+            instrumentor.handle_write(flushed);
+        }
+    }
+
+    /// Adds `delta` to the running total of the counter `series` and writes
+    /// the new total, plotted as a graph by the viewers. Totals start at 0 in
+    /// every session. Only JSON sessions record counters.
+    pub fn counter_add(series: &str, delta: f64) {
+        let now = Instant::now();
+        let mut instrumentor = lock_instrumentor();
+        if instrumentor.current_session.is_none() {
+            instrumentor.late_events += 1;
+            return;
+        }
+        if !instrumentor.session_format().is_json() {
+            return;
+        }
+
+        let total = match instrumentor.counters.get_mut(series) {
+            Some(total) => {
+                *total += delta;
+                *total
+            }
+            None => {
+                instrumentor.counters.insert(series.to_string(), delta);
+                delta
+            }
+        };
+        let ts = instrumentor.session_micros(now);
+        instrumentor.index_event(ts, ts);
+        let written = instrumentor.write_chrome_event(|stream| {
+            write!(stream, "{{\"args\":{{\"{}\":", escape_json(series))?;
+            ArgValue::Float(total).write_json(stream)?;
+            write!(
+                stream,
+                "}},\"cat\":\"counter\",\"name\":\"{}\",\"ph\":\"C\",\"pid\":0,\"ts\":{}}}",
+                escape_json(series),
+                ts,
+            )
+        });
+        instrumentor.handle_write(written);
+    }
+
+    /// Writes an event made of exactly `fields`, in that order, for the
+    /// phases there's no dedicated API for. JSON sessions only.
+    ///
+    /// Nothing is checked beyond the JSON being valid: producing a field
+    /// combination viewers understand, including `ph`, `pid`, `tid` and a `ts`
+    /// matching the session's clock, is up to the caller.
+    pub fn write_raw_event(fields: &[(&str, JsonValue)]) {
+        let mut instrumentor = lock_instrumentor();
+        if instrumentor.current_session.is_none() {
+            instrumentor.late_events += 1;
+            return;
+        }
+        if !instrumentor.session_format().is_json() {
+            return;
+        }
+
+        let number = |key| match fields.iter().find(|(k, _)| *k == key) {
+            Some((_, JsonValue::Value(ArgValue::Int(n)))) => Some(*n),
+            Some((_, JsonValue::Value(ArgValue::Float(n)))) => Some(*n as i64),
+            _ => None,
+        };
+        if let Some(ts) = number("ts") {
+            instrumentor.index_event(ts, ts.saturating_add(number("dur").unwrap_or(0)));
+        }
+        let written = instrumentor.write_chrome_event(|stream| write_json_object(stream, fields));
+        instrumentor.handle_write(written);
+    }
+
+    /// Adds `duration` to the stats of `name` on the calling thread, as if a
+    /// span of that length was recorded, without writing any event. Shows
+    /// up in [`Instrumentor::summary`] and [`TraceFormat::Totals`] files.
+    pub fn accumulate(name: &str, duration: Duration) {
+        let mut instrumentor = lock_instrumentor();
+        if instrumentor.current_session.is_none() {
+            instrumentor.late_events += 1;
+            return;
+        }
+        let name = filtered_name(name).into_owned();
+        instrumentor
+            .summary
+            .entry((current_track_id(), name))
+            .or_default()
+            .record(duration_to_micros(duration));
+    }
+
+    pub fn write_profile(result: &ProfileResult) {
+        let mut instrumentor = lock_instrumentor();
+        instrumentor.internal_write_profile(result);
+    }
+
+    /// Number of events dropped because they were recorded after the last
+    /// session's footer was written, typically by spans still running on
+    /// other threads when it ended.
+    pub fn late_event_count() -> usize {
+        lock_instrumentor().late_events
+    }
+
+    /// The error that stopped the current or last session from writing, if
+    /// any. After a write fails, e.g. because the disk is full, the footer is
+    /// still attempted so the events written so far may load, and every event
+    /// after that is dropped.
+    ///
+    /// Failing that, it's the first problem the session ran into that didn't
+    /// stop it, like a shard that couldn't be merged or a span lasting
+    /// implausibly long, which hints at a clock bug.
+    pub fn last_error() -> Option<io::Error> {
+        let instrumentor = lock_instrumentor();
+        instrumentor
+            .last_error
+            .as_ref()
+            .map(|err| io::Error::new(err.kind(), err.to_string()))
+    }
+
+    /// Number of events written by the current session, or by the last one
+    /// once it has ended, including metadata like thread names.
+    pub fn event_count() -> usize {
+        lock_instrumentor().profile_count.load(Ordering::Relaxed)
+    }
+
+    /// The span recorded last in the current or last session, with its name
+    /// already filtered. Handy for checking that instrumentation fires at all.
+    pub fn last_event() -> Option<ProfileResult> {
+        lock_instrumentor().last_event.clone()
+    }
+
+    /// Name, event count, duration and size of the last ended session, e.g.
+    /// for printing a summary line without reading the trace back.
+    pub fn last_session_stats() -> Option<SessionStats> {
+        lock_instrumentor().last_session.clone()
+    }
+
+    /// Clears what a session recorded about itself, like its event count,
+    /// summaries, percentiles and retained events, as soon as it ends rather
+    /// than when the next one begins. Its [`Instrumentor::last_session_stats`]
+    /// and [`Instrumentor::last_error`] are kept either way. Off by default.
+    pub fn set_reset_on_end(reset: bool) {
+        lock_instrumentor().reset_on_end = reset;
+    }
+
+    /// Per-name statistics of the current session, or of the last one once it
+    /// has ended. Durations are in microseconds.
+    pub fn summary() -> HashMap<String, NameStats> {
+        let instrumentor = lock_instrumentor();
+        let mut summary: HashMap<String, NameStats> = HashMap::new();
+        for ((_, name), stats) in &instrumentor.summary {
+            summary.entry(name.clone()).or_default().merge(stats);
+        }
+        summary
+    }
+
+    /// Approximate p50, p90 and p99 durations of the spans named `name` in
+    /// the current or last session, within about 6% of the exact values.
+    pub fn percentiles(name: &str) -> Option<Percentiles> {
+        let instrumentor = lock_instrumentor();
+        instrumentor
+            .histograms
+            .get(name)
+            .map(histogram::Histogram::percentiles)
+    }
+
+    /// Writes [`Instrumentor::percentiles`] of every span name to a CSV file
+    /// at `path`, one row per name, e.g. to compare builds in CI.
+    pub fn write_percentiles_csv(path: &str) -> io::Result<()> {
+        let histograms = lock_instrumentor().histograms.clone();
+        histogram::write_csv(path, histograms.iter())
+    }
+
+    /// Like [`Instrumentor::summary`], but only for the spans tagged `tag`,
+    /// e.g. by [`tracing_tagged!`].
+    pub fn summary_by_tag(tag: &str) -> HashMap<String, NameStats> {
+        let instrumentor = lock_instrumentor();
+        instrumentor
+            .tag_summary
+            .iter()
+            .filter(|((t, _), _)| *t == tag)
+            .map(|((_, name), stats)| (name.clone(), *stats))
+            .collect()
+    }
+
+    /// Like [`Instrumentor::summary`], but spans with the same name on
+    /// different threads are kept apart.
+    pub fn summary_by_thread() -> HashMap<(u32, String), NameStats> {
+        lock_instrumentor().summary.clone()
+    }
+
+    /// Compares the spans of the JSON traces at `baseline_path` and
+    /// `candidate_path` by name, e.g. before and after an optimization. The
+    /// names with the largest change in total time come first. Both traces
+    /// are streamed.
+    pub fn diff_traces(baseline_path: &str, candidate_path: &str) -> io::Result<Vec<NameDiff>> {
+        analysis::diff_traces(baseline_path, candidate_path)
+    }
+
+    /// Writes `diffs` from [`Instrumentor::diff_traces`] as CSV with one row
+    /// per name, in the same order.
+    pub fn write_diff_csv(diffs: &[NameDiff], path: &str) -> io::Result<()> {
+        analysis::write_diff_csv(diffs, path)
+    }
+
+    /// The longest chain of nested spans among the retained events of the
+    /// current or last session: the longest top-level span, then its longest
+    /// child and so on. Empty unless [`Instrumentor::set_retain_events`] was
+    /// enabled.
+    pub fn critical_path() -> Vec<ProfileResult> {
+        analysis::critical_path(&lock_instrumentor().events)
+    }
+
+    /// Turns the JSON trace at `trace_path` into a single HTML file at
+    /// `html_path` that embeds the trace and a small viewer drawing its
+    /// spans, so the trace can be shared as one double-clickable file that
+    /// works offline. The page can also save the trace back out as JSON or
+    /// load it into ui.perfetto.dev.
+    pub fn export_html(trace_path: &str, html_path: &str) -> io::Result<()> {
+        export::export_html(trace_path, html_path)
+    }
+
+    /// Converts a [`TraceFormat::Binary`] trace at `in_path` into a JSON trace
+    /// at `out_path`. A trace cut off by a crash is converted up to its last
+    /// complete record.
+    pub fn binary_to_chrome(in_path: &str, out_path: &str) -> io::Result<()> {
+        binary::binary_to_chrome(in_path, out_path)
+    }
+
+    /// Writes a copy of the JSON trace at `in_path` to `out_path` with every
+    /// `ts` shifted so the earliest one is 0, e.g. for a window cut out of a
+    /// longer trace, which would otherwise show up after a long gap. Order
+    /// and durations are unchanged, and the header's `clockSync` is shifted
+    /// along so wall clock times stay right. The input is read twice.
+    pub fn rebase_trace(in_path: &str, out_path: &str) -> io::Result<()> {
+        rewrite::rebase_trace(in_path, out_path)
+    }
+
+    /// Writes a copy of the JSON trace at `in_path` to `out_path` without the
+    /// spans shorter than `min_duration_micros`, e.g. to make a trace too
+    /// large for the viewer loadable. The input is streamed, so it may be
+    /// larger than memory. Events without a duration are kept.
+    pub fn downsample_trace(
+        in_path: &str,
+        out_path: &str,
+        min_duration_micros: i64,
+    ) -> io::Result<()> {
+        rewrite::downsample_trace(in_path, out_path, min_duration_micros)
+    }
+
+    /// The spans of a complete JSON session in `bytes`, for tests asserting on
+    /// a trace instead of opening it in a viewer, e.g. one captured with
+    /// [`Instrumentor::begin_session_with_writer`]. Fails with
+    /// [`ParseError::Framing`] if the header or the footer is missing or the
+    /// events aren't separated by exactly one comma, with
+    /// [`ParseError::Truncated`] if the trace ends early, and with
+    /// [`ParseError::Json`] if a span can't be read.
+    ///
+    /// Only complete (`"ph":"X"`) events are returned, in file order. Traces
+    /// don't store [`ProfileResult::depth`], it's always 0.
+    pub fn parse_session_bytes(bytes: &[u8]) -> Result<Vec<ProfileResult>, ParseError> {
+        let session = reader::session_events(bytes, false)?;
+        spans(&session.events)
+    }
+
+    /// Like [`Instrumentor::parse_session_bytes`], but a trace that ends
+    /// early, e.g. because the process crashed during its session, isn't an
+    /// error. The spans written in full are returned, a half written last
+    /// event is skipped.
+    pub fn parse_truncated_session_bytes(bytes: &[u8]) -> Result<RecoveredSession, ParseError> {
+        let session = reader::session_events(bytes, true)?;
+        Ok(RecoveredSession {
+            spans: spans(&session.events)?,
+            truncated: session.truncated,
+        })
+    }
+
+    /// Copies the JSON trace at `in_path` to `out_path`, making it loadable if
+    /// it ends early: a half written last event is dropped and the footer is
+    /// added. Returns whether the trace was truncated. The `stackFrames` of
+    /// the footer are lost if it was cut off.
+    pub fn repair_trace(in_path: &str, out_path: &str) -> io::Result<bool> {
+        rewrite::repair_trace(in_path, out_path)
+    }
+
+    /// The raw JSON of the events of the trace at `trace_path` that overlap
+    /// `[t0_micros, t1_micros]`. Uses the index written with
+    /// [`Instrumentor::enable_index`] to skip everything that ended before
+    /// `t0_micros`, and to stop reading once nothing later can overlap the
+    /// window. Events without a timestamp, like thread names, aren't
+    /// returned.
+    pub fn read_trace_window(
+        trace_path: &str,
+        t0_micros: i64,
+        t1_micros: i64,
+    ) -> io::Result<Vec<String>> {
+        index::read_window(trace_path, t0_micros, t1_micros)
+    }
+
+    /// Merges the JSON traces at `in_paths`, e.g. recorded on different
+    /// hosts, into one trace at `out_path`. Every trace becomes its own
+    /// process, named after its path, and its timestamps are shifted so that
+    /// all traces share the wall clock basis recorded when their sessions
+    /// began. Traces without that clock sync record aren't shifted. Samples
+    /// are dropped, their stack frame ids would clash.
+    ///
+    /// Alignment is only as good as the hosts' wall clocks agreed when the
+    /// sessions began, typically within a few milliseconds under NTP. On top
+    /// of that each host's monotonic clock drifts from the others over a
+    /// session, usually by tens of microseconds per second.
+    pub fn merge_traces(in_paths: &[&str], out_path: &str) -> io::Result<()> {
+        rewrite::merge_traces(in_paths, out_path)
+    }
+
+    /// Splits the JSON trace at `in_path` into one trace per thread in
+    /// `out_dir`, named `<pid>-<tid>.json`, and returns their paths. Every
+    /// thread keeps its own events, its name included, and every output gets
+    /// the events not on any thread. The input is streamed, with one output
+    /// open per thread. Fails on a `pid` or `tid` that isn't a number.
+    pub fn split_trace_by_thread(in_path: &str, out_dir: &str) -> io::Result<Vec<String>> {
+        rewrite::split_trace_by_thread(in_path, out_dir)
+    }
+
+    /// Runs `f` as a span named `name`, catching a panic in it. The span gets
+    /// a `panicked` arg telling whether it did, and ends once the unwinding
+    /// reached this call. Panics in `f` don't end the session of
+    /// [`Instrumentor::install_panic_hook`].
+    pub fn profile_catch<R>(
+        name: &str,
+        f: impl FnOnce() -> R + std::panic::UnwindSafe,
+    ) -> std::thread::Result<R> {
+        let mut timer = InstrumentationTimer::new(name);
+        CATCHING.set(CATCHING.get() + 1);
+        let result = std::panic::catch_unwind(f);
+        CATCHING.set(CATCHING.get() - 1);
+        timer
+            .args
+            .push(("panicked".to_string(), result.is_err().into()));
+        timer.stop();
+        result
+    }
+
+    /// Runs `f` inside its own session and returns its result.
+    ///
+    /// The session is ended when `f` returns or unwinds, so the trace is
+    /// always closed properly. Fails if the output file can't be created or
+    /// another session is already active.
+    pub fn with_session<R>(name: &str, filepath: &str, f: impl FnOnce() -> R) -> io::Result<R> {
+        let mut instrumentor = lock_instrumentor();
+        instrumentor.internal_begin_session(name, filepath)?;
+        let _guard = SessionGuard::<()>::new(instrumentor.session_id);
+        drop(instrumentor);
+        Ok(f())
+    }
+
+    /// Wall clock time of the current session's start, in microseconds since
+    /// the Unix epoch.
+    fn session_unix_micros(&self) -> i64 {
+        let since_start = self
+            .current_session
+            .as_ref()
+            .map_or(Duration::ZERO, |session| session.start.elapsed());
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| {
+                i64::try_from(now.saturating_sub(since_start).as_micros()).unwrap_or(i64::MAX)
+            })
+    }
+
+    /// Microseconds between the start of the current session and `instant`.
+    fn session_micros(&self, instant: Instant) -> i64 {
+        self.current_session.as_ref().map_or(0, |session| {
+            duration_to_micros(instant.saturating_duration_since(session.start))
+        })
+    }
+
+    /// See [`session_span`]. Without a session the span starts at 0.
+    fn session_span(&self, start: Instant, duration: i64) -> (i64, i64) {
+        match &self.current_session {
+            Some(session) => session_span(session.start, start, duration),
+            None => (0, duration),
+        }
+    }
+
+    fn internal_begin_session(&mut self, name: &str, filepath: &str) -> io::Result<()> {
+        self.check_no_session()?;
+        let file = if self.fail_if_exists {
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(filepath)?
+        } else {
+            File::create(filepath)?
+        };
+        let index = match self.index_bucket {
+            Some(bucket) if self.format.is_json() => Some(index::TraceIndex::create(
+                &index::index_path(filepath),
+                duration_to_micros(bucket),
+            )?),
+            _ => None,
+        };
+        self.start_session(name, Some(filepath), Output::File(file))?;
+        self.index = index;
+        Ok(())
+    }
+
+    fn check_no_session(&self) -> io::Result<()> {
+        match self.current_session {
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "a session is already active",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Resets everything describing the previous session. Unless
+    /// [`Instrumentor::set_reset_on_end`] is on, it's kept around until then
+    /// so it can be inspected after `end_session`.
+    fn start_session(
+        &mut self,
+        name: &str,
+        filepath: Option<&str>,
+        output: Output,
+    ) -> io::Result<()> {
+        // Spans batched after the last session ended belong to neither.
+        self.write_local_spans();
+        self.reset_session_stats();
+        self.last_end.clear();
+        self.named_threads.clear();
+        self.last_error = None;
+        self.stack_frames.clear();
+        self.stack_frame_ids.clear();
+        self.session_id += 1;
+        self.output_stream = Some(OutputStream::new(output));
+        self.current_session = Some(InstrumentationSession {
+            name: name.to_string(),
+            filepath: filepath.map(str::to_string),
+            format: self.format,
+            pretty: self.pretty,
+            start: Instant::now(),
+        });
+        self.start_unix_micros = self.session_unix_micros();
+        if let Err(err) = self.write_header() {
+            self.output_stream = None;
+            self.current_session = None;
+            return Err(err);
+        }
+        if self.sampling_interval.is_some() {
+            self.start_sampler();
+        }
+        self.start_flusher();
+        if let Some(capacity) = self.async_capacity {
+            self.writer = Some(queue::Writer::spawn(capacity, self.overflow_policy));
+        }
+        self.write_session_marker("SessionBegin");
+        if self.sharded && self.session_format().is_json() {
+            shard::begin(self.current_session.as_ref().unwrap().start);
+        }
+        for observer in &mut self.observers {
+            run_callback(|| observer.on_begin(name));
+        }
+        Ok(())
+    }
+
+    fn start_flusher(&mut self) {
+        if let (Some(interval), None) = (self.flush_interval, &self.flusher) {
+            self.flusher = Some(flusher::Flusher::spawn(interval));
+        }
+    }
+
+    /// The session's background threads, to be stopped once the lock is
+    /// released.
+    fn take_threads(
+        &mut self,
+    ) -> (
+        Option<sampling::Sampler>,
+        Option<flusher::Flusher>,
+        Option<queue::Writer>,
+    ) {
+        (self.sampler.take(), self.flusher.take(), self.writer.take())
+    }
+
+    fn start_sampler(&mut self) {
+        if let Some(interval) = self.sampling_interval {
+            if self.session_format().is_json() {
+                self.sampler = Some(sampling::Sampler::spawn(interval));
+            }
+        }
+    }
+
+    /// Writes one sample event per thread, `stacks` holding the open spans of
+    /// each thread outermost first.
+    fn write_samples(stacks: Vec<(u32, Vec<String>)>) {
+        let mut instrumentor = lock_instrumentor();
+        if instrumentor.output_stream.is_none() {
+            return;
+        }
+
+        let ts = instrumentor.session_micros(Instant::now());
+        for (thread_id, stack) in stacks {
+            let frame = stack
+                .iter()
+                .fold(0, |parent, name| instrumentor.stack_frame_id(parent, name));
+            instrumentor.index_event(ts, ts);
+            let written = instrumentor.write_chrome_event(|stream| {
+                write!(
+                    stream,
+                    "{{\"cat\":\"sample\",\"name\":\"sample\",\"ph\":\"P\",\"pid\":0,\"sf\":{},\"tid\":{},\"ts\":{}}}",
+                    frame, thread_id, ts,
+                )
+            });
+            instrumentor.handle_write(written);
+        }
+    }
+
+    fn stack_frame_id(&mut self, parent: u32, name: &str) -> u32 {
+        let name = filtered_name(name).into_owned();
+        if let Some(&id) = self.stack_frame_ids.get(&(parent, name.clone())) {
+            return id;
+        }
+        self.stack_frames.push((parent, name.clone()));
+        let id = self.stack_frames.len() as u32;
+        self.stack_frame_ids.insert((parent, name), id);
+        id
+    }
+
+    fn internal_end_session(&mut self) {
+        if self.current_session.is_some() {
+            self.write_local_spans();
+            let shards = shard::end();
+            if let Err(err) = self.merge_shards(&shards) {
+                self.last_error.get_or_insert(err);
+            }
+            for shard in shards {
+                let _ = std::fs::remove_file(&shard.path);
+                if let Some(err) = shard.error {
+                    self.last_error.get_or_insert(err);
+                }
+            }
+            self.write_session_marker("SessionEnd");
+            if let Err(err) = self.write_footer() {
+                self.last_error = Some(err);
+            }
+            self.close_stream();
+            if let Some(index) = self.index.take() {
+                if let Err(err) = index.finish() {
+                    self.last_error.get_or_insert(err);
+                }
+            }
+            if let Some(session) = self.current_session.take() {
+                self.last_session = Some(SessionStats {
+                    name: session.name,
+                    event_count: self.profile_count.load(Ordering::Relaxed),
+                    duration: session.start.elapsed(),
+                    bytes: self.output_len,
+                });
+                for observer in &mut self.observers {
+                    run_callback(|| observer.on_end());
+                }
+            }
+            if self.reset_on_end {
+                self.reset_session_stats();
+            }
+        }
+    }
+
+    /// Clears what a session recorded about itself for inspection.
+    fn reset_session_stats(&mut self) {
+        self.profile_count.store(0, Ordering::Relaxed);
+        COLLAPSED.store(0, Ordering::Relaxed);
+        self.output_len = 0;
+        self.summary.clear();
+        self.tag_summary.clear();
+        self.histograms.clear();
+        self.counters.clear();
+        self.events.clear();
+        self.last_event = None;
+        self.late_events = 0;
+    }
+
+    fn write_session_marker(&mut self, name: &str) {
+        if !self.emit_session_markers || !self.session_format().is_json() {
+            return;
+        }
+        let now = Instant::now();
+        let ts = self.session_micros(now);
+        let uptime = process_start().map(|start| duration_to_micros(now.duration_since(start)));
+        self.index_event(ts, ts);
+        let written = self.write_chrome_event(|stream| {
+            write!(stream, "{{\"args\":{{")?;
+            if let Some(uptime) = uptime {
+                write!(stream, "\"uptimeMicros\":{}", uptime)?;
+            }
+            write!(
+                stream,
+                "}},\"cat\":\"session\",\"name\":\"{}\",\"ph\":\"i\",\"pid\":0,\"s\":\"g\",\"tid\":{},\"ts\":{}}}",
+                name,
+                current_thread_id(),
+                ts,
+            )
+        });
+        self.handle_write(written);
+    }
+
+    /// Appends the events of `shards` to the trace.
+    fn merge_shards(&mut self, shards: &[shard::ShardFile]) -> io::Result<()> {
+        // Everything from here on is in no particular order.
+        self.index_with(|index, offset| index.record_tail(offset));
+        let mut sorted = Vec::new();
+        for shard in shards {
+            let file = BufReader::new(File::open(&shard.path)?.chain(&b"]"[..]));
+            let mut events = reader::TraceReader::at_event(file);
+            while let Some(event) = events.next_event()? {
+                if self.sort_shards {
+                    let ts = reader::field(&event, "ts").and_then(|ts| ts.parse::<i64>().ok());
+                    sorted.push((ts, event));
+                } else {
+                    self.write_raw_chrome_event(&event)?;
+                }
+            }
+        }
+
+        // Thread names have no ts and go first.
+        sorted.sort_by_key(|(ts, _)| *ts);
+        for (_, event) in sorted {
+            self.write_raw_chrome_event(&event)?;
+        }
+        Ok(())
+    }
+
+    fn write_raw_chrome_event(&mut self, event: &str) -> io::Result<()> {
+        self.write_chrome_event(|stream| stream.write_all(event.as_bytes()))
+    }
+
+    fn close_stream(&mut self) {
+        if let Some(file) = self.output_stream.take() {
+            let mut file = file.file.into_inner().unwrap();
+            let _ = file.flush();
+            match file.into_parts().0 {
+                Output::File(file) => {
+                    self.output_len = file
+                        .metadata()
+                        .ok()
+                        .filter(|metadata| metadata.is_file())
+                        .map_or(0, |metadata| metadata.len());
+                }
+                Output::Writer(writer) => {
+                    self.finished_writer = Some((self.session_id, writer.into_any()))
+                }
+            }
+        }
+    }
+
+    fn session_format(&self) -> TraceFormat {
+        self.current_session
+            .as_ref()
+            .map_or(self.format, |session| session.format)
+    }
+
+    fn session_pretty(&self) -> bool {
+        self.current_session
+            .as_ref()
+            .is_some_and(|session| session.pretty)
+    }
+
+    fn internal_write_profile(&mut self, result: &ProfileResult) {
+        if self.current_session.is_none() {
+            self.late_events += 1;
+            return;
+        }
+        if self.output_stream.is_none() || !self.is_recorded(result) {
+            return;
+        }
+        if result.duration() > IMPLAUSIBLE_MICROS {
+            self.last_error.get_or_insert_with(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "span `{}` lasted an implausible {} µs, clock bug?",
+                        result.name,
+                        result.duration()
+                    ),
+                )
+            });
+        }
+
+        if let Some(idle) = self.idle_event(result) {
+            self.write_event(&idle, "idle");
+        }
+        if result.depth == 0 {
+            self.last_end.insert(result.thread_id, result.end);
+        }
+
+        let seq = self.profile_count.load(Ordering::Relaxed);
+        let result = self.written_event(result, seq);
+        let result = result.as_ref();
+        self.write_event(result, "function");
+        for observer in &mut self.observers {
+            run_callback(|| observer.on_event(result));
+        }
+        self.summary
+            .entry((result.thread_id, result.name.clone()))
+            .or_default()
+            .record(result.duration());
+        for tag in &result.tags {
+            self.tag_summary
+                .entry((tag, result.name.clone()))
+                .or_default()
+                .record(result.duration());
+        }
+        match self.histograms.get_mut(&result.name) {
+            Some(histogram) => histogram.record(result.duration()),
+            None => {
+                let mut histogram = histogram::Histogram::default();
+                histogram.record(result.duration());
+                self.histograms.insert(result.name.clone(), histogram);
+            }
+        }
+        if self.retain_events {
+            self.events.push(result.clone());
+        }
+        self.last_event = Some(result.clone());
+    }
+
+    /// Whether the session's record window and predicate let `result` in.
+    fn is_recorded(&self, result: &ProfileResult) -> bool {
+        if let Some((start, end)) = self.record_window {
+            if !(start..=end).contains(&result.start) {
+                return false;
+            }
+        }
+        match &self.record_predicate {
+            Some(predicate) => run_callback(|| predicate(&result.name)) != Some(false),
+            None => true,
+        }
+    }
+
+    /// The idle event written before `result` if [`Instrumentor::set_emit_idle`]
+    /// is on and its thread was idle long enough.
+    fn idle_event(&self, result: &ProfileResult) -> Option<ProfileResult> {
+        // Spans are written as they end, so children show up before their
+        // parent. Only the gap between top-level spans is known not to overlap
+        // anything else on the thread.
+        if !self.emit_idle || result.depth != 0 {
+            return None;
+        }
+        let idle_start = *self.last_end.get(&result.thread_id)?;
+        (result.start.saturating_sub(idle_start) > IDLE_THRESHOLD_MICROS).then(|| ProfileResult {
+            name: "idle".to_string(),
+            start: idle_start,
+            end: result.start,
+            thread_id: result.thread_id,
+            depth: 0,
+            args: Vec::new(),
+            tags: Vec::new(),
+        })
+    }
+
+    /// `result` as the session writes it, with its name filtered and the
+    /// `wall` and `seq` args added if they're on, `seq` being its number.
+    fn written_event<'a>(&self, result: &'a ProfileResult, seq: usize) -> Cow<'a, ProfileResult> {
+        let mut result = match filtered_name(&result.name) {
+            Cow::Owned(name) => Cow::Owned(ProfileResult {
+                name,
+                ..result.clone()
+            }),
+            Cow::Borrowed(_) => Cow::Borrowed(result),
+        };
+        if self.record_walltime {
+            let wall = self.start_unix_micros.saturating_add(result.start);
+            result
+                .to_mut()
+                .args
+                .push(("wall".to_string(), ArgValue::Int(wall)));
+        }
+        if self.stamp_sequence {
+            result
+                .to_mut()
+                .args
+                .push(("seq".to_string(), ArgValue::Int(seq as i64)));
+        }
+        result
+    }
+
+    /// See [`ProfileResult::encoded_len`].
+    fn event_len(&self, result: &ProfileResult, format: TraceFormat) -> usize {
+        let Some(output) = &self.output_stream else {
+            return 0;
+        };
+        if self.current_session.is_none() || !self.is_recorded(result) {
+            return 0;
+        }
+        let idle = self.idle_event(result);
+        // Writing the idle event takes a number too.
+        let seq = self.profile_count.load(Ordering::Relaxed) + usize::from(idle.is_some());
+        let result = self.written_event(result, seq);
+        let events: Vec<(&ProfileResult, &str)> = idle
+            .iter()
+            .map(|idle| (idle, "idle"))
+            .chain([(result.as_ref(), "function")])
+            .collect();
+        match format {
+            TraceFormat::ChromeTracing | TraceFormat::PerfettoJson => {
+                let mut counter = ByteCounter(0);
+                for (i, (event, category)) in events.into_iter().enumerate() {
+                    if i > 0 || output.wrote_first_event.load(Ordering::Relaxed) {
+                        counter.0 += 1;
+                    }
+                    if self.session_pretty() {
+                        counter.0 += 3;
+                    }
+                    event.write_chrome_json(&mut counter, category).unwrap();
+                }
+                counter.0
+            }
+            #[cfg(feature = "perfetto")]
+            TraceFormat::PerfettoProto => self.perfetto.spans_len(&events),
+            TraceFormat::Binary => self.binary.spans_len(&events),
+            TraceFormat::Totals => 0,
+        }
+    }
+
+    /// Names the calling thread's row in the viewer the first time it records
+    /// a span in a session, using the name it was spawned with if it has one.
+    fn name_current_thread(&mut self, thread_id: u32) {
+        if self.current_session.is_none() || !self.named_threads.insert(thread_id) {
+            return;
+        }
+
+        let name = current_thread_name(thread_id);
+        self.write_thread_name(thread_id, &name, &current_thread_metadata());
+    }
+
+    fn write_thread_name(&mut self, thread_id: u32, name: &str, args: &[(String, ArgValue)]) {
+        let written = match self.session_format() {
+            TraceFormat::ChromeTracing | TraceFormat::PerfettoJson => self
+                .write_chrome_event(|stream| write_thread_name_json(stream, thread_id, name, args)),
+            #[cfg(feature = "perfetto")]
+            TraceFormat::PerfettoProto => match self.output_stream {
+                Some(ref mut stream) => {
+                    let stream = stream.file.get_mut().unwrap();
+                    self.perfetto.write_thread_name(stream, thread_id, name)
+                }
+                None => Ok(()),
+            },
+            TraceFormat::Binary => match self.output_stream {
+                Some(ref mut stream) => {
+                    let stream = stream.file.get_mut().unwrap();
+                    self.binary.write_thread_name(stream, thread_id, name)
+                }
+                None => Ok(()),
+            },
+            TraceFormat::Totals => Ok(()),
+        };
+        self.handle_write(written);
+    }
+
+    /// Writes what timers queued, see [`Instrumentor::set_async_writing`].
+    fn write_queued(&mut self, (names, spans): queue::Batch) {
+        for (thread_id, name, args) in names {
+            if self.current_session.is_some() && self.named_threads.insert(thread_id) {
+                self.write_thread_name(thread_id, &name, &args);
+            }
+        }
+        for pending in spans {
+            self.write_pending(pending);
+        }
+    }
+
+    fn write_pending(&mut self, pending: queue::Pending) {
+        let (start, end) = self.session_span(pending.start, pending.duration);
+        self.internal_write_profile(&ProfileResult {
+            name: pending.name,
+            start,
+            end,
+            thread_id: pending.thread_id,
+            depth: pending.depth,
+            args: pending.args,
+            tags: pending.tags.into_owned(),
+        });
+    }
+
+    fn write_event(&mut self, result: &ProfileResult, category: &str) {
+        let written = match self.session_format() {
+            TraceFormat::ChromeTracing | TraceFormat::PerfettoJson => {
+                self.index_event(result.start, result.end);
+                self.write_chrome_event(|stream| result.write_chrome_json(stream, category))
+            }
+            #[cfg(feature = "perfetto")]
+            TraceFormat::PerfettoProto => self.write_perfetto_span(result, category),
+            TraceFormat::Binary => self.write_binary_span(result, category),
+            TraceFormat::Totals => Ok(()),
+        };
+        self.handle_write(written);
+    }
+
+    /// Adds the event from `start` to `end` about to be written to the
+    /// index.
+    fn index_event(&mut self, start: i64, end: i64) {
+        self.index_with(|index, offset| index.record(start, end, offset));
+    }
+
+    /// Calls `record` with the index and the offset the next event will be
+    /// written at. A failing index is dropped, the trace itself is still fine
+    /// without it.
+    fn index_with(&mut self, record: impl FnOnce(&mut index::TraceIndex, u64) -> io::Result<()>) {
+        if let (Some(index), Some(stream)) = (&mut self.index, &mut self.output_stream) {
+            // Seeking the buffered writer would flush it, so this asks the
+            // file and adds what is still buffered.
+            let stream = stream.file.get_mut().unwrap();
+            let buffered = stream.buffer().len() as u64;
+            // Only sessions begun with a path have an index.
+            let Output::File(file) = stream.get_mut() else {
+                return;
+            };
+            if file
+                .stream_position()
+                .and_then(|offset| record(index, offset + buffered))
+                .is_err()
+            {
+                self.index = None;
+            }
+        }
+    }
+
+    /// Stops writing to a stream after a failed write, so a full disk doesn't
+    /// take the program down with it.
+    fn handle_write(&mut self, written: io::Result<()>) {
+        if let Err(err) = written {
+            let _ = self.write_footer();
+            self.close_stream();
+            self.last_error = Some(err);
+        }
+    }
+
+    /// Writes a single JSON event, `body` writing the event object itself.
+    fn write_chrome_event(
+        &mut self,
+        body: impl FnOnce(&mut BufWriter<Output>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let pretty = self.session_pretty();
+        if let Some(ref mut output) = self.output_stream {
+            let stream = output.file.get_mut().unwrap();
+            if output.wrote_first_event.swap(true, Ordering::Relaxed) {
+                write!(stream, ",")?;
+            }
+            if pretty {
+                write!(stream, "\n  ")?;
+            }
+
+            body(stream)?;
+
+            output.event_written(self.flush_every)?;
+            self.profile_count.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "perfetto")]
+    fn write_perfetto_span(&mut self, result: &ProfileResult, category: &str) -> io::Result<()> {
+        if let Some(ref mut output) = self.output_stream {
+            let stream = output.file.get_mut().unwrap();
+            self.perfetto.write_span(stream, result, category)?;
+            output.event_written(self.flush_every)?;
+            self.profile_count.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn write_binary_span(&mut self, result: &ProfileResult, category: &str) -> io::Result<()> {
+        if let Some(ref mut output) = self.output_stream {
+            let stream = output.file.get_mut().unwrap();
+            self.binary.write_span(stream, result, category)?;
+            output.event_written(self.flush_every)?;
+            self.profile_count.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let format = self.session_format();
+        let unix_micros = self.start_unix_micros;
+        if let Some(ref mut stream) = self.output_stream {
+            let stream = stream.file.get_mut().unwrap();
+            match format {
+                TraceFormat::ChromeTracing => write_chrome_header(
+                    stream,
+                    SCHEMA_VERSION,
+                    Some(unix_micros),
+                    self.emit_environment,
+                )?,
+                // Timestamps are relative to the session start rather than
+                // any system clock, which no `clock-domain` value names, so
+                // `metadata`, the otherData of this format, says so instead.
+                TraceFormat::PerfettoJson => {
+                    write!(stream, "{{\"displayTimeUnit\":\"ms\",\"metadata\":{{")?;
+                    write_clock_sync(stream, unix_micros)?;
+                    if self.emit_environment {
+                        write!(stream, ",")?;
+                        write_environment(stream)?;
+                    }
+                    write!(
+                        stream,
+                        ",\"schema\":{},\"simpleTracingVersion\":\"{}\",\"tsOrigin\":\"sessionStart\"}},\"systemTraceEvents\":\"\",\"traceEvents\":[",
+                        SCHEMA_VERSION,
+                        env!("CARGO_PKG_VERSION"),
+                    )?;
+                }
+                #[cfg(feature = "perfetto")]
+                TraceFormat::PerfettoProto => self.perfetto.write_header(stream)?,
+                TraceFormat::Binary => self.binary.write_header(stream)?,
+                TraceFormat::Totals => writeln!(stream, "name,count,total_us")?,
+            }
+            stream.flush()?;
+        }
+        Ok(())
+    }
+
+    fn write_footer(&mut self) -> io::Result<()> {
+        let format = self.session_format();
+        let pretty = self.session_pretty();
+        if let Some(ref mut stream) = self.output_stream {
+            let stream = stream.file.get_mut().unwrap();
+            match format {
+                TraceFormat::ChromeTracing | TraceFormat::PerfettoJson => {
+                    if pretty {
+                        writeln!(stream)?;
+                    }
+                    write!(stream, "]")?;
+                    if !self.stack_frames.is_empty() {
+                        write!(stream, ",\"stackFrames\":{{")?;
+                        for (i, (parent, name)) in self.stack_frames.iter().enumerate() {
+                            if i > 0 {
+                                write!(stream, ",")?;
+                            }
+                            write!(
+                                stream,
+                                "\"{}\":{{\"category\":\"function\",\"name\":\"{}\"",
+                                i + 1,
+                                escape_json(name)
+                            )?;
+                            if *parent != 0 {
+                                write!(stream, ",\"parent\":{}", parent)?;
+                            }
+                            write!(stream, "}}")?;
+                        }
+                        write!(stream, "}}")?;
+                    }
+                    write!(stream, "}}")?;
+                }
+                // A protobuf trace is just a sequence of packets, there's
+                // nothing to close once the held back spans are written.
+                #[cfg(feature = "perfetto")]
+                TraceFormat::PerfettoProto => self.perfetto.write_pending(stream)?,
+                TraceFormat::Binary => {}
+                TraceFormat::Totals => {
+                    let mut totals: HashMap<&str, NameStats> = HashMap::new();
+                    for ((_, name), stats) in &self.summary {
+                        totals.entry(name).or_default().merge(stats);
+                    }
+                    let mut rows: Vec<_> = totals.into_iter().collect();
+                    rows.sort_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(b.0)));
+                    for (name, stats) in rows {
+                        writeln!(
+                            stream,
+                            "{},{},{}",
+                            histogram::csv_field(name),
+                            stats.count,
+                            stats.total
+                        )?;
+                    }
+                }
+            }
+            stream.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// A session's output file.
+struct OutputStream {
+    file: Mutex<BufWriter<Output>>,
+    /// Whether an event was written to this file yet, if not the next one
+    /// goes without a separating comma.
+    wrote_first_event: AtomicBool,
+    /// Events written since the last flush.
+    unflushed: usize,
+}
+
+impl OutputStream {
+    fn new(output: Output) -> Self {
+        OutputStream {
+            file: Mutex::new(BufWriter::new(output)),
+            wrote_first_event: AtomicBool::new(false),
+            unflushed: 0,
+        }
+    }
+
+    /// Flushes once `flush_every` events were written since the last flush,
+    /// see [`Instrumentor::set_flush_every`].
+    fn event_written(&mut self, flush_every: usize) -> io::Result<()> {
+        self.unflushed += 1;
+        if flush_every > 0 && self.unflushed >= flush_every {
+            self.file.get_mut().unwrap().flush()?;
+            self.unflushed = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Where a session's trace goes.
+enum Output {
+    File(File),
+    Writer(Box<dyn AnyWrite>),
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::File(file) => file.write(buf),
+            Output::Writer(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::File(file) => file.flush(),
+            Output::Writer(writer) => writer.flush(),
+        }
+    }
+}
+
+/// A writer that can be handed back as its own type once its session ended.
+trait AnyWrite: Write + Send {
+    fn into_any(self: Box<Self>) -> Box<dyn Any + Send>;
+}
+
+impl<W: Write + Send + 'static> AnyWrite for W {
+    fn into_any(self: Box<Self>) -> Box<dyn Any + Send> {
+        self
+    }
+}
+
+/// The spans among the raw JSON `events`.
+fn spans(events: &[String]) -> Result<Vec<ProfileResult>, ParseError> {
+    let mut spans = Vec::new();
+    for event in events {
+        spans.extend(reader::span(event).map_err(|err| ParseError::Json(err.to_string()))?);
+    }
+    Ok(spans)
+}
+
+/// Ends the session it was returned for when dropped, including during
+/// unwinding, so the trace gets its footer even if `end_session` is never
+/// reached. A session begun after that one ended is left running.
+///
+/// Returned by [`Instrumentor::begin_session_with_writer`], `W` being the
+/// writer's type.
+#[must_use = "dropping the guard ends the session right away"]
+pub struct SessionGuard<W = ()> {
+    /// Id of the guarded session, see [`Instrumentor::session_id`].
+    session_id: u64,
+    finished: bool,
+    writer: PhantomData<fn() -> W>,
+}
+
+impl<W> SessionGuard<W> {
+    fn new(session_id: u64) -> Self {
+        SessionGuard {
+            session_id,
+            finished: false,
+            writer: PhantomData,
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> SessionGuard<W> {
+    /// Ends the session and hands back its writer, with the complete trace
+    /// written to it.
+    ///
+    /// Returns `None` if the writer is gone: when the session was ended
+    /// early and another writer session began since.
+    pub fn finish(mut self) -> Option<W> {
+        self.finished = true;
+        Instrumentor::end_session_with_id(Some(self.session_id));
+        let mut instrumentor = lock_instrumentor();
+        match instrumentor.finished_writer.take() {
+            Some((id, writer)) if id == self.session_id => {
+                writer.downcast().ok().map(|writer| *writer)
+            }
+            other => {
+                instrumentor.finished_writer = other;
+                None
+            }
+        }
+    }
+}
+
+impl<W> Drop for SessionGuard<W> {
+    fn drop(&mut self) {
+        if !self.finished {
+            Instrumentor::end_session_with_id(Some(self.session_id));
+        }
+    }
+}
+
+impl<'a> Drop for InstrumentationTimer<'a> {
+    fn drop(&mut self) {
+        if !self.stopped {
+            self.stop();
+        }
+    }
+}
+
+/// See [`Instrumentor::set_strict_nesting`]. Kept outside the instrumentor so
+/// timers can check it without locking.
+static STRICT_NESTING: AtomicBool = AtomicBool::new(false);
+
+/// See [`Instrumentor::set_single_threaded`].
+static SINGLE_THREADED: AtomicBool = AtomicBool::new(false);
+
+/// See [`Instrumentor::set_enabled`].
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// See [`Instrumentor::set_name_filter`]. Kept outside the instrumentor so
+/// sharded sessions can filter names without locking it.
+static NAME_FILTER: RwLock<Option<Arc<NameFilter>>> = RwLock::new(None);
+
+/// See [`Instrumentor::set_max_name_len`].
+static MAX_NAME_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// `name` as it's written: rewritten by the name filter and cut down to the
+/// maximum length.
+fn filtered_name(name: &str) -> Cow<'_, str> {
+    // Cloned out, so a filter setting another filter doesn't deadlock.
+    let filter = NAME_FILTER
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    let name = match filter {
+        Some(filter) => run_callback(|| filter(name)).unwrap_or(Cow::Borrowed(name)),
+        None => Cow::Borrowed(name),
+    };
+    let max_name_len = MAX_NAME_LEN.load(Ordering::Relaxed);
+    if max_name_len == 0 {
+        return name;
+    }
+    match name.char_indices().nth(max_name_len - 1) {
+        Some((end, _)) if name[end..].chars().nth(1).is_some() => {
+            Cow::Owned(format!("{}…", &name[..end]))
+        }
+        _ => name,
+    }
+}
+
+/// See [`Instrumentor::set_collapse_recursion`].
+static COLLAPSE_RECURSION: AtomicBool = AtomicBool::new(false);
+
+/// See [`Instrumentor::collapsed_count`].
+static COLLAPSED: AtomicUsize = AtomicUsize::new(0);
+
+/// See [`Instrumentor::set_capture_backtrace_over`], `i64::MAX` when off.
+static BACKTRACE_THRESHOLD: AtomicI64 = AtomicI64::new(i64::MAX);
+
+/// Most frames kept in a span's backtrace.
+const MAX_BACKTRACE_FRAMES: usize = 32;
+
+/// The caller's backtrace without the frames of the timer itself and of the
+/// backtrace machinery.
+fn trimmed_backtrace() -> String {
+    let backtrace = std::backtrace::Backtrace::force_capture().to_string();
+    let mut frames: Vec<String> = Vec::new();
+    for line in backtrace.lines() {
+        let trimmed = line.trim_start();
+        let starts_frame = trimmed
+            .split_once(": ")
+            .is_some_and(|(index, _)| index.bytes().all(|b| b.is_ascii_digit()));
+        match frames.last_mut() {
+            Some(frame) if !starts_frame => {
+                frame.push('\n');
+                frame.push_str(trimmed);
+            }
+            _ => frames.push(trimmed.to_string()),
+        }
+    }
+
+    let internal = |frame: &String| {
+        frame.contains("std::backtrace")
+            || frame.contains("simple_tracing::trimmed_backtrace")
+            || frame.contains("simple_tracing::InstrumentationTimer")
+            || frame.contains("core::ptr::drop_in_place")
+    };
+    let start = frames.iter().rposition(internal).map_or(0, |i| i + 1);
+    // Everything below the entry point is the runtime's.
+    frames[start..]
+        .iter()
+        .take_while(|frame| !frame.contains("__rust_begin_short_backtrace"))
+        .take(MAX_BACKTRACE_FRAMES)
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+thread_local! {
+    /// Number of timers started but not yet stopped on this thread.
+    static OPEN_TIMERS: Cell<u32> = const { Cell::new(0) };
+    static CURRENT_TRACK: Cell<Option<u32>> = const { Cell::new(None) };
+    static CORRELATION_ID: RefCell<Option<CorrelationId>> = const { RefCell::new(None) };
+    /// Names of the timers open on this thread while
+    /// [`Instrumentor::set_collapse_recursion`] is on, innermost last.
+    static OPEN_NAMES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    /// Number of [`Instrumentor::profile_catch`] calls running on this thread.
+    static CATCHING: Cell<u32> = const { Cell::new(0) };
+}
+
+pub struct InstrumentationTimer<'a> {
+    name: &'a str,
+    start_timepoint: Option<Instant>,
+    depth: u32,
+    sampled: bool,
+    stopped: bool,
+    args: Vec<(String, ArgValue)>,
+    tags: Cow<'static, [&'static str]>,
+    /// Whether the timer is on [`OPEN_NAMES`], and if so whether it's named
+    /// like its parent and so isn't written.
+    collapsed: Option<bool>,
+    #[cfg(feature = "track-alloc")]
+    /// The thread's allocation counter when the timer started.
+    allocated_at_start: Option<u64>,
+    /// The signpost interval begun for the timer, if signposts were on.
+    #[cfg(all(feature = "signpost", target_os = "macos"))]
+    signpost: Option<signpost::Interval>,
+    /// The `trace_marker` slice begun for the timer, if ftrace was on.
+    #[cfg(all(feature = "ftrace", target_os = "linux"))]
+    ftrace: Option<ftrace::Slice>,
+    /// The ETW interval begun for the timer, if a trace session recorded it.
+    #[cfg(all(feature = "etw", windows))]
+    etw: Option<etw::Interval>,
+    /// Keeps the timer on the thread it started on, whose thread-locals,
+    /// like [`OPEN_TIMERS`], track it until it stops.
+    thread_bound: PhantomData<*const ()>,
+}
+
+impl<'a> InstrumentationTimer<'a> {
+    pub fn new(name: &'a str) -> Self {
+        Self::with_args(name, Vec::new())
+    }
+
+    /// A timer that started at `start`, captured earlier with
+    /// `Instant::now()` where a timer couldn't be held. It still nests under
+    /// the spans open when it's created. A start before the session began is
+    /// clamped to the session start, keeping the end where it was.
+    pub fn from_start(name: &'a str, start: Instant) -> Self {
+        let mut timer = Self::new(name);
+        if timer.start_timepoint.is_some() {
+            timer.start_timepoint = Some(start);
+        }
+        timer
+    }
+
+    /// Like [`InstrumentationTimer::new`], with `tags` recorded with the span.
+    pub fn with_tags(name: &'a str, tags: &'static [&'static str]) -> Self {
+        let mut timer = Self::new(name);
+        timer.tags = Cow::Borrowed(tags);
+        timer
+    }
+
+    /// Like [`InstrumentationTimer::new`], with `args` written with the span.
+    pub fn with_args(name: &'a str, args: Vec<(String, ArgValue)>) -> Self {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return InstrumentationTimer {
+                name,
+                start_timepoint: None,
+                depth: 0,
+                sampled: false,
+                stopped: true,
+                args,
+                tags: Cow::Borrowed(&[]),
+                collapsed: None,
+                #[cfg(feature = "track-alloc")]
+                allocated_at_start: None,
+                #[cfg(all(feature = "signpost", target_os = "macos"))]
+                signpost: None,
+                #[cfg(all(feature = "ftrace", target_os = "linux"))]
+                ftrace: None,
+                #[cfg(all(feature = "etw", windows))]
+                etw: None,
+                thread_bound: PhantomData,
+            };
+        }
+        let depth = OPEN_TIMERS.get();
+        OPEN_TIMERS.set(depth + 1);
+        InstrumentationTimer {
+            name,
+            start_timepoint: Some(Instant::now()),
+            depth,
+            sampled: sampling::push(name),
+            stopped: false,
+            args,
+            tags: Cow::Borrowed(&[]),
+            collapsed: COLLAPSE_RECURSION.load(Ordering::Relaxed).then(|| {
+                OPEN_NAMES.with_borrow_mut(|names| {
+                    let collapsed = names.last().is_some_and(|parent| parent == name);
+                    names.push(name.to_string());
+                    collapsed
+                })
+            }),
+            #[cfg(feature = "track-alloc")]
+            allocated_at_start: alloc::allocated(),
+            #[cfg(all(feature = "signpost", target_os = "macos"))]
+            signpost: signpost::begin(name),
+            #[cfg(all(feature = "ftrace", target_os = "linux"))]
+            ftrace: ftrace::begin(name),
+            #[cfg(all(feature = "etw", windows))]
+            etw: etw::begin(name),
+            thread_bound: PhantomData,
+        }
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(start_timepoint) = self.start_timepoint.take() {
+            let end_timepoint = Instant::now();
+            #[cfg(all(feature = "ftrace", target_os = "linux"))]
+            if let Some(slice) = self.ftrace.take() {
+                ftrace::end(slice);
+            }
+            #[cfg(all(feature = "etw", windows))]
+            if let Some(interval) = self.etw.take() {
+                etw::end(interval, self.name);
+            }
+            #[cfg(all(feature = "signpost", target_os = "macos"))]
+            if let Some(interval) = self.signpost.take() {
+                signpost::end(interval);
+            }
+            let open_timers = OPEN_TIMERS.get();
+            OPEN_TIMERS.set(open_timers.saturating_sub(1));
+            // Popped before the nesting check, so a caught panic leaves this
+            // thread's stacks in step with its open timers.
+            if self.sampled {
+                sampling::pop();
+            }
+            if self.collapsed.is_some() {
+                OPEN_NAMES.with_borrow_mut(Vec::pop);
+            }
+            if open_timers != self.depth + 1
+                && STRICT_NESTING.load(Ordering::Relaxed)
+                && !std::thread::panicking()
+            {
+                panic!(
+                    "span `{}` ended while {} span(s) started inside it were still open",
+                    self.name,
+                    open_timers.saturating_sub(self.depth + 1),
+                );
+            }
+            if self.collapsed == Some(true) {
+                COLLAPSED.fetch_add(1, Ordering::Relaxed);
+                self.stopped = true;
+                return;
+            }
+            let elapsed = end_timepoint.duration_since(start_timepoint);
+
+            let duration = duration_to_micros(elapsed);
+
+            let thread_id = current_track_id();
+
+            let mut args = std::mem::take(&mut self.args);
+            #[cfg(feature = "track-alloc")]
+            if let (Some(start), Some(end)) = (self.allocated_at_start, alloc::allocated()) {
+                args.push(("alloc_bytes".to_string(), end.saturating_sub(start).into()));
+            }
+            if duration > BACKTRACE_THRESHOLD.load(Ordering::Relaxed) {
+                args.push(("backtrace".to_string(), trimmed_backtrace().into()));
+            }
+            CORRELATION_ID.with_borrow(|id| {
+                if let Some(id) = id {
+                    args.push(("trace_id".to_string(), id.trace_id.as_str().into()));
+                    if let Some(span_id) = &id.span_id {
+                        args.push(("span_id".to_string(), span_id.as_str().into()));
+                    }
+                }
+            });
+
+            let args = match shard::record(
+                self.name,
+                start_timepoint,
+                duration,
+                thread_id,
+                self.depth,
+                args,
+                &self.tags,
+            ) {
+                Ok(()) => {
+                    self.stopped = true;
+                    return;
+                }
+                Err(args) => args,
+            };
+
+            let pending = queue::Pending {
+                name: self.name.to_string(),
+                start: start_timepoint,
+                duration,
+                thread_id,
+                depth: self.depth,
+                args,
+                tags: std::mem::take(&mut self.tags),
+            };
+            if SINGLE_THREADED.load(Ordering::Relaxed) {
+                local::record(pending);
+                self.stopped = true;
+                return;
+            }
+            let args = match queue::push(pending) {
+                Ok(()) => {
+                    self.stopped = true;
+                    return;
+                }
+                Err(pending) => {
+                    self.tags = pending.tags;
+                    pending.args
+                }
+            };
+
+            // The start is made session-relative under the same lock the
+            // event is written with, so a session ending or starting in
+            // between can't pair it with the wrong session.
+            let mut instrumentor = lock_instrumentor();
+            let (start, end) = instrumentor.session_span(start_timepoint, duration);
+            instrumentor.name_current_thread(thread_id);
+            instrumentor.internal_write_profile(&ProfileResult {
+                name: self.name.to_string(),
+                start,
+                end,
+                thread_id,
+                depth: self.depth,
+                args,
+                tags: self.tags.to_vec(),
+            });
+
+            self.stopped = true;
+        }
+    }
+}
+
+/// A span started by [`Instrumentor::span`], written when it's dropped with
+/// the args and tags set on it by then.
+pub struct Span<'a> {
+    timer: InstrumentationTimer<'a>,
+}
+
+impl Span<'_> {
+    /// Records `key` with the span, replacing the value it was set to before.
+    pub fn set_arg(&mut self, key: &str, value: impl Into<ArgValue>) {
+        let value = value.into();
+        match self.timer.args.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value,
+            None => self.timer.args.push((key.to_string(), value)),
+        }
+    }
+
+    /// Records `tag` with the span, once however often it's added.
+    pub fn add_tag(&mut self, tag: &'static str) {
+        if !self.timer.tags.contains(&tag) {
+            self.timer.tags.to_mut().push(tag);
+        }
+    }
+
+    /// Ends the span before the end of its scope.
+    pub fn end(mut self) {
+        self.timer.stop();
+    }
+}
+
+/// Times the rest of the enclosing scope as a span named `$name`, with
+/// optional `key = value` args.
+///
+/// Every invocation gets its own hygienic binding, and shadowing doesn't drop
+/// a value, so several `tracing!` in one scope all last until its end.
+///
+/// This form is a `let` statement: it times what follows it in the block, so
+/// as the last statement of a block it times nothing, and it doesn't compile
+/// where an expression is expected, like a match arm without braces. There,
+/// `tracing!($name => $body)` times just the expression `$body` and
+/// evaluates to its value, e.g. `_ => tracing!("parse" => parse(input)),`.
+#[macro_export]
+macro_rules! tracing {
+    ($name:expr => $body:expr) => {{
+        let _timer = $crate::InstrumentationTimer::new($name);
+        $body
+    }};
+    ($name:expr, $($key:ident = $value:expr),+ => $body:expr) => {{
+        let _timer = $crate::InstrumentationTimer::with_args(
+            $name,
+            vec![$((stringify!($key).to_string(), $crate::ArgValue::from($value))),+],
+        );
+        $body
+    }};
+    ($name:expr) => {
+        let _timer = $crate::InstrumentationTimer::new($name);
+    };
+    ($name:expr, $($key:ident = $value:expr),+ $(,)?) => {
+        let _timer = $crate::InstrumentationTimer::with_args(
+            $name,
+            vec![$((stringify!($key).to_string(), $crate::ArgValue::from($value))),+],
+        );
+    };
+}
+
+/// Like [`tracing!`], but only times the scope if `$cond` is true. When it's
+/// false no timer is created at all, so nothing is measured or written.
+#[macro_export]
+macro_rules! tracing_if {
+    ($cond:expr, $name:expr) => {
+        let _timer = if $cond {
+            Some($crate::InstrumentationTimer::new($name))
+        } else {
+            None
+        };
+    };
+    ($cond:expr, $name:expr, $($key:ident = $value:expr),+ $(,)?) => {
+        let _timer = if $cond {
+            Some($crate::InstrumentationTimer::with_args(
+                $name,
+                vec![$((stringify!($key).to_string(), $crate::ArgValue::from($value))),+],
+            ))
+        } else {
+            None
+        };
+    };
+}
+
+/// Like [`tracing!`], recording the span with the tags `[$tag, ...]`, e.g.
+/// `tracing_tagged!("read", ["io", "hot"])`.
+#[macro_export]
+macro_rules! tracing_tagged {
+    ($name:expr, [$($tag:expr),* $(,)?]) => {
+        let _timer = $crate::InstrumentationTimer::with_tags($name, &[$($tag),*]);
+    };
+}
+
+/// Like [`tracing!`] for the body of a loop, naming the span `$name[$index]`
+/// so each iteration shows up as a span of its own, e.g. `iter[3]`.
+#[macro_export]
+macro_rules! tracing_loop {
+    ($name:expr, $index:expr) => {
+        let _name = format!("{}[{}]", $name, $index);
+        let _timer = $crate::InstrumentationTimer::new(&_name);
+    };
+    ($name:expr, $index:expr, $($key:ident = $value:expr),+ $(,)?) => {
+        let _name = format!("{}[{}]", $name, $index);
+        let _timer = $crate::InstrumentationTimer::with_args(
+            &_name,
+            vec![$((stringify!($key).to_string(), $crate::ArgValue::from($value))),+],
+        );
+    };
+}
+
+/// Same as [`tracing!`], but only records in debug builds. In release builds
+/// it expands to nothing, or to just `$body` for the `=>` forms.
+#[macro_export]
+macro_rules! debug_tracing {
+    ($name:expr $(, $key:ident = $value:expr)* => $body:expr) => {{
+        #[cfg(debug_assertions)]
+        let _timer = $crate::InstrumentationTimer::with_args(
+            $name,
+            vec![$((stringify!($key).to_string(), $crate::ArgValue::from($value))),*],
+        );
+        $body
+    }};
+    ($($args:tt)+) => {
+        #[cfg(debug_assertions)]
+        $crate::tracing!($($args)+);
+    };
+}
@@ -0,0 +1,72 @@
+//! Batching for programs recording from a single thread: timers collect
+//! their spans on the thread and take the instrumentor's lock once per batch.
+//!
+//! Each thread's batch is registered, so spans batched by other threads than
+//! the one asserted still end up in the session they were recorded in.
+
+use std::{
+    cell::RefCell,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    lock_instrumentor,
+    queue::{Batch, Pending},
+    Instrumentor,
+};
+
+/// Spans collected before a batch is written.
+const BATCH_LEN: usize = 256;
+
+type SharedBatch = Arc<Mutex<Batch>>;
+
+/// The batch of every thread that recorded a span. A batch whose thread has
+/// exited is dropped once it was written.
+static BATCHES: Mutex<Vec<SharedBatch>> = Mutex::new(Vec::new());
+
+thread_local! {
+    static BATCH: RefCell<Option<SharedBatch>> = const { RefCell::new(None) };
+}
+
+/// Adds a span to the calling thread's batch, writing the batches once it's
+/// full.
+pub(crate) fn record(pending: Pending) {
+    let full = BATCH.with_borrow_mut(|batch| {
+        let batch = batch.get_or_insert_with(|| {
+            let batch = SharedBatch::default();
+            BATCHES.lock().unwrap().push(batch.clone());
+            batch
+        });
+        let (names, spans) = &mut *batch.lock().unwrap();
+        // The writing thread can't look up this one's name, and the batch
+        // may be written into a session this thread hasn't named itself in.
+        if spans.is_empty() {
+            let name = crate::current_thread_name(pending.thread_id);
+            names.push((pending.thread_id, name, crate::current_thread_metadata()));
+        }
+        spans.push(pending);
+        spans.len() >= BATCH_LEN
+    });
+    if full {
+        lock_instrumentor().write_local_spans();
+    }
+}
+
+impl Instrumentor {
+    /// Writes the batch of every thread, see
+    /// [`Instrumentor::set_single_threaded`].
+    pub(crate) fn write_local_spans(&mut self) {
+        let batches: Vec<Batch> = {
+            let mut registry = BATCHES.lock().unwrap();
+            let batches = registry
+                .iter()
+                .map(|batch| std::mem::take(&mut *batch.lock().unwrap()))
+                .collect();
+            registry.retain(|batch| Arc::strong_count(batch) > 1);
+            batches
+        };
+        for batch in batches {
+            self.write_queued(batch);
+        }
+    }
+}
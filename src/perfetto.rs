@@ -0,0 +1,386 @@
+//! Perfetto protobuf sink.
+//!
+//! Only the handful of `TracePacket` fields we actually emit are modelled
+//! here, using the tag numbers from Perfetto's `trace_packet.proto`. A trace
+//! file is a `Trace` message, which is just `repeated TracePacket packet = 1`,
+//! so packets can be streamed out one `Trace` at a time and still concatenate
+//! into a valid file.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use prost::Message;
+
+use crate::{ArgValue, ByteCounter, ProfileResult};
+
+const BUILTIN_CLOCK_REALTIME: u32 = 1;
+const BUILTIN_CLOCK_BOOTTIME: u32 = 6;
+
+const TYPE_SLICE_BEGIN: i32 = 1;
+const TYPE_SLICE_END: i32 = 2;
+
+const SEQ_INCREMENTAL_STATE_CLEARED: u32 = 1;
+
+/// All packets are written from behind the instrumentor lock, so a single
+/// sequence is enough.
+const SEQUENCE_ID: u32 = 1;
+
+#[derive(Clone, PartialEq, Message)]
+struct Trace {
+    #[prost(message, repeated, tag = "1")]
+    packet: Vec<TracePacket>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TracePacket {
+    #[prost(message, optional, tag = "6")]
+    clock_snapshot: Option<ClockSnapshot>,
+    #[prost(uint64, optional, tag = "8")]
+    timestamp: Option<u64>,
+    #[prost(uint32, optional, tag = "10")]
+    trusted_packet_sequence_id: Option<u32>,
+    #[prost(message, optional, tag = "11")]
+    track_event: Option<TrackEvent>,
+    #[prost(uint32, optional, tag = "13")]
+    sequence_flags: Option<u32>,
+    #[prost(message, optional, tag = "60")]
+    track_descriptor: Option<TrackDescriptor>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ClockSnapshot {
+    #[prost(message, repeated, tag = "1")]
+    clocks: Vec<Clock>,
+    #[prost(uint32, optional, tag = "2")]
+    primary_trace_clock: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Clock {
+    #[prost(uint32, optional, tag = "1")]
+    clock_id: Option<u32>,
+    #[prost(uint64, optional, tag = "2")]
+    timestamp: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TrackDescriptor {
+    #[prost(uint64, optional, tag = "1")]
+    uuid: Option<u64>,
+    #[prost(message, optional, tag = "4")]
+    thread: Option<ThreadDescriptor>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ThreadDescriptor {
+    #[prost(int32, optional, tag = "1")]
+    pid: Option<i32>,
+    #[prost(int32, optional, tag = "2")]
+    tid: Option<i32>,
+    #[prost(string, optional, tag = "5")]
+    thread_name: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct DebugAnnotation {
+    #[prost(bool, optional, tag = "2")]
+    bool_value: Option<bool>,
+    #[prost(int64, optional, tag = "4")]
+    int_value: Option<i64>,
+    #[prost(double, optional, tag = "5")]
+    double_value: Option<f64>,
+    #[prost(string, optional, tag = "6")]
+    string_value: Option<String>,
+    #[prost(string, optional, tag = "10")]
+    name: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TrackEvent {
+    #[prost(message, repeated, tag = "4")]
+    debug_annotations: Vec<DebugAnnotation>,
+    #[prost(enumeration = "i32", optional, tag = "9")]
+    r#type: Option<i32>,
+    #[prost(uint64, optional, tag = "11")]
+    track_uuid: Option<u64>,
+    #[prost(string, repeated, tag = "22")]
+    categories: Vec<String>,
+    #[prost(string, optional, tag = "23")]
+    name: Option<String>,
+}
+
+/// Per-session state for the Perfetto sink.
+#[derive(Default)]
+pub(crate) struct PerfettoState {
+    seen_threads: HashSet<u32>,
+    /// Spans of each thread waiting for the top-level span they're nested
+    /// in, with their category.
+    pending: HashMap<u32, Vec<(ProfileResult, String)>>,
+}
+
+impl PerfettoState {
+    /// Writes the clock snapshot that anchors session-relative timestamps to
+    /// wall-clock time.
+    pub(crate) fn write_header(&mut self, stream: &mut impl Write) -> io::Result<()> {
+        self.seen_threads.clear();
+        self.pending.clear();
+
+        let realtime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+
+        write_packet(
+            stream,
+            TracePacket {
+                clock_snapshot: Some(ClockSnapshot {
+                    clocks: vec![
+                        Clock {
+                            clock_id: Some(BUILTIN_CLOCK_BOOTTIME),
+                            timestamp: Some(0),
+                        },
+                        Clock {
+                            clock_id: Some(BUILTIN_CLOCK_REALTIME),
+                            timestamp: Some(realtime),
+                        },
+                    ],
+                    primary_trace_clock: Some(BUILTIN_CLOCK_BOOTTIME),
+                }),
+                timestamp: Some(0),
+                sequence_flags: Some(SEQ_INCREMENTAL_STATE_CLEARED),
+                ..packet()
+            },
+        )
+    }
+
+    /// Writes the descriptor of a thread's track, naming it `name`. Must come
+    /// before the thread's first span to take effect.
+    pub(crate) fn write_thread_name(
+        &mut self,
+        stream: &mut impl Write,
+        thread_id: u32,
+        name: &str,
+    ) -> io::Result<()> {
+        self.seen_threads.insert(thread_id);
+        write_thread_descriptor(stream, thread_id, Some(name.to_string()))
+    }
+
+    /// Encoded size of the packets written for `spans` of one thread and
+    /// their categories, with the thread's descriptor if it's new.
+    pub(crate) fn spans_len(&self, spans: &[(&ProfileResult, &str)]) -> usize {
+        let descriptor = match spans.first() {
+            Some((result, _)) if !self.seen_threads.contains(&result.thread_id) => {
+                let mut counter = ByteCounter(0);
+                write_thread_descriptor(&mut counter, result.thread_id, None).unwrap();
+                counter.0
+            }
+            _ => 0,
+        };
+        let slices: usize = spans
+            .iter()
+            .map(|(result, category)| span_len(result, category))
+            .sum();
+        descriptor + slices
+    }
+
+    /// Writes a begin/end slice pair for `result`, preceded by a thread
+    /// descriptor the first time its thread is seen.
+    ///
+    /// Spans end innermost first, but the trace processor nests slices by
+    /// the order of their begin and end packets, so a child written before
+    /// its parent would close the parent whenever both begin in the same
+    /// microsecond. Nested spans are therefore held back until the thread's
+    /// top-level span ends, and its whole tree is written outermost first.
+    /// Idle events are written before the top-level span that ends them and
+    /// are held back too, so they don't write the tree before its root.
+    pub(crate) fn write_span(
+        &mut self,
+        stream: &mut impl Write,
+        result: &ProfileResult,
+        category: &str,
+    ) -> io::Result<()> {
+        if self.seen_threads.insert(result.thread_id) {
+            write_thread_descriptor(stream, result.thread_id, None)?;
+        }
+
+        let pending = self.pending.entry(result.thread_id).or_default();
+        pending.push((result.clone(), category.to_string()));
+        if result.depth > 0 || category == "idle" {
+            return Ok(());
+        }
+        let spans = std::mem::take(pending);
+        write_tree(stream, spans)
+    }
+
+    /// Writes the spans still waiting for their top-level span, at the end of
+    /// the session.
+    pub(crate) fn write_pending(&mut self, stream: &mut impl Write) -> io::Result<()> {
+        for (_, spans) in std::mem::take(&mut self.pending) {
+            write_tree(stream, spans)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the slices of one thread's `spans`, nested by their depth, each
+/// begin followed by those of its children and only then its end. A child's
+/// end is clamped to its parent's, which rounding to microseconds can put
+/// before the child's.
+fn write_tree(stream: &mut impl Write, mut spans: Vec<(ProfileResult, String)>) -> io::Result<()> {
+    spans.sort_by_key(|(result, _)| (result.start, result.depth));
+    // Depth, end and track of the slices begun but not yet ended, innermost
+    // last.
+    let mut open: Vec<(u32, i64, u32)> = Vec::new();
+    for (result, category) in &spans {
+        while let Some(&(depth, end, thread_id)) = open.last() {
+            if depth < result.depth {
+                break;
+            }
+            open.pop();
+            write_packet(stream, end_packet(thread_id, end))?;
+        }
+        let end = open
+            .last()
+            .map_or(result.end, |&(_, parent_end, _)| result.end.min(parent_end));
+        write_packet(stream, begin_packet(result, category))?;
+        open.push((result.depth, end, result.thread_id));
+    }
+    while let Some((_, end, thread_id)) = open.pop() {
+        write_packet(stream, end_packet(thread_id, end))?;
+    }
+    Ok(())
+}
+
+/// Encoded size of the slice packets written for `result`.
+fn span_len(result: &ProfileResult, category: &str) -> usize {
+    [
+        begin_packet(result, category),
+        end_packet(result.thread_id, result.end),
+    ]
+    .into_iter()
+    .map(|packet| {
+        Trace {
+            packet: vec![packet],
+        }
+        .encoded_len()
+    })
+    .sum()
+}
+
+fn begin_packet(result: &ProfileResult, category: &str) -> TracePacket {
+    TracePacket {
+        timestamp: Some(micros_to_nanos(result.start)),
+        track_event: Some(TrackEvent {
+            r#type: Some(TYPE_SLICE_BEGIN),
+            track_uuid: Some(result.thread_id as u64),
+            categories: std::iter::once(category)
+                .chain(result.tags.iter().copied())
+                .map(str::to_string)
+                .collect(),
+            name: Some(result.name.clone()),
+            debug_annotations: result
+                .args
+                .iter()
+                .map(|(key, value)| debug_annotation(key, value))
+                .collect(),
+        }),
+        ..packet()
+    }
+}
+
+fn end_packet(thread_id: u32, end: i64) -> TracePacket {
+    TracePacket {
+        timestamp: Some(micros_to_nanos(end)),
+        track_event: Some(TrackEvent {
+            r#type: Some(TYPE_SLICE_END),
+            track_uuid: Some(thread_id as u64),
+            ..Default::default()
+        }),
+        ..packet()
+    }
+}
+
+fn debug_annotation(key: &str, value: &ArgValue) -> DebugAnnotation {
+    let mut annotation = DebugAnnotation {
+        name: Some(key.to_string()),
+        ..Default::default()
+    };
+    match value {
+        ArgValue::Str(value) => annotation.string_value = Some(value.clone()),
+        ArgValue::Int(value) => annotation.int_value = Some(*value),
+        ArgValue::Float(value) => annotation.double_value = Some(*value),
+        ArgValue::Bool(value) => annotation.bool_value = Some(*value),
+    }
+    annotation
+}
+
+fn write_thread_descriptor(
+    stream: &mut impl Write,
+    thread_id: u32,
+    thread_name: Option<String>,
+) -> io::Result<()> {
+    write_packet(
+        stream,
+        TracePacket {
+            track_descriptor: Some(TrackDescriptor {
+                uuid: Some(thread_id as u64),
+                thread: Some(ThreadDescriptor {
+                    pid: Some(std::process::id() as i32),
+                    tid: Some(thread_id as i32),
+                    thread_name,
+                }),
+            }),
+            ..packet()
+        },
+    )
+}
+
+fn packet() -> TracePacket {
+    TracePacket {
+        trusted_packet_sequence_id: Some(SEQUENCE_ID),
+        ..Default::default()
+    }
+}
+
+fn write_packet(stream: &mut impl Write, packet: TracePacket) -> io::Result<()> {
+    let trace = Trace {
+        packet: vec![packet],
+    };
+    stream.write_all(&trace.encode_to_vec())
+}
+
+fn micros_to_nanos(micros: i64) -> u64 {
+    (micros.max(0) as u64).saturating_mul(1000)
+}
+
+/// The slices of a trace written by this module as `(name, depth, start,
+/// end)` in nanoseconds, nested the way the trace processor does: packets
+/// stably sorted by timestamp, each end closing the innermost open slice of
+/// its track.
+#[cfg(test)]
+pub(crate) fn decode_slices(bytes: &[u8]) -> Vec<(String, usize, u64, u64)> {
+    let trace = Trace::decode(bytes).unwrap();
+    let mut packets: Vec<_> = trace
+        .packet
+        .into_iter()
+        .filter_map(|packet| Some((packet.timestamp?, packet.track_event?)))
+        .collect();
+    packets.sort_by_key(|(timestamp, _)| *timestamp);
+
+    let mut open: HashMap<u64, Vec<(String, u64)>> = HashMap::new();
+    let mut slices = Vec::new();
+    for (timestamp, event) in packets {
+        let stack = open.entry(event.track_uuid.unwrap()).or_default();
+        match event.r#type {
+            Some(TYPE_SLICE_BEGIN) => stack.push((event.name.unwrap(), timestamp)),
+            Some(TYPE_SLICE_END) => {
+                let (name, start) = stack.pop().unwrap();
+                slices.push((name, stack.len(), start, timestamp));
+            }
+            _ => {}
+        }
+    }
+    slices
+}
@@ -0,0 +1,179 @@
+//! Background writing: timers hand their spans to a bounded queue instead of
+//! waiting on the instrumentor's lock, and a thread writes them out.
+
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Condvar, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use crate::{lock_instrumentor, ArgValue};
+
+/// What a timer does when the queue of
+/// [`crate::Instrumentor::set_async_writing`] is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Waits for the writer to make room, so no span is lost.
+    #[default]
+    Block,
+    /// Drops the span that didn't fit.
+    DropNewest,
+    /// Drops the oldest queued span to make room.
+    DropOldest,
+}
+
+/// A span waiting to be written.
+pub(crate) struct Pending {
+    pub(crate) name: String,
+    pub(crate) start: Instant,
+    pub(crate) duration: i64,
+    pub(crate) thread_id: u32,
+    pub(crate) depth: u32,
+    pub(crate) args: Vec<(String, ArgValue)>,
+    pub(crate) tags: Cow<'static, [&'static str]>,
+}
+
+/// A thread's id, name and metadata, captured when it queues its first span
+/// in a session since the writer can't look them up. Kept apart from the
+/// spans so that none are dropped.
+pub(crate) type ThreadName = (u32, String, Vec<(String, ArgValue)>);
+
+/// What the writer takes from the queue in one go.
+pub(crate) type Batch = (Vec<ThreadName>, Vec<Pending>);
+
+struct State {
+    names: Vec<ThreadName>,
+    events: VecDeque<Pending>,
+    /// Whether a writer is running and takes spans.
+    open: bool,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Counts the writers started, for threads to tell whether they sent
+    /// their name to the current one.
+    generation: u64,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    names: Vec::new(),
+    events: VecDeque::new(),
+    open: false,
+    capacity: 0,
+    policy: OverflowPolicy::Block,
+    generation: 0,
+});
+static PUSHED: Condvar = Condvar::new();
+static POPPED: Condvar = Condvar::new();
+
+/// Spans dropped by the current or last writer, see
+/// [`crate::Instrumentor::dropped_count`].
+pub(crate) static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static NAMED_IN: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+/// Queues a span for the writer. Hands it back if no writer is running,
+/// for it to be written the usual way.
+pub(crate) fn push(pending: Pending) -> Result<(), Pending> {
+    let mut state = STATE.lock().unwrap();
+    if !state.open {
+        return Err(pending);
+    }
+    while state.events.len() >= state.capacity {
+        match state.policy {
+            OverflowPolicy::Block => {
+                state = POPPED.wait(state).unwrap();
+                if !state.open {
+                    return Err(pending);
+                }
+            }
+            OverflowPolicy::DropNewest => {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+            OverflowPolicy::DropOldest => {
+                state.events.pop_front();
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    let generation = state.generation;
+    if NAMED_IN.get() != generation {
+        NAMED_IN.set(generation);
+        let name = crate::current_thread_name(pending.thread_id);
+        let args = crate::current_thread_metadata();
+        state.names.push((pending.thread_id, name, args));
+    }
+    state.events.push_back(pending);
+    PUSHED.notify_one();
+    Ok(())
+}
+
+/// Stops taking spans and returns those still queued, for when the writer
+/// can't be waited for.
+pub(crate) fn close() -> Batch {
+    take(&mut shut())
+}
+
+fn take(state: &mut State) -> Batch {
+    (
+        std::mem::take(&mut state.names),
+        state.events.drain(..).collect(),
+    )
+}
+
+/// Stops taking spans, waking up the writer and the timers waiting for room.
+fn shut() -> MutexGuard<'static, State> {
+    let mut state = STATE.lock().unwrap();
+    state.open = false;
+    POPPED.notify_all();
+    PUSHED.notify_all();
+    state
+}
+
+pub(crate) struct Writer {
+    handle: JoinHandle<()>,
+}
+
+/// Starts taking up to `capacity` spans, for a new writer.
+pub(crate) fn open(capacity: usize, policy: OverflowPolicy) {
+    let mut state = STATE.lock().unwrap();
+    state.open = true;
+    state.capacity = capacity.max(1);
+    state.policy = policy;
+    state.generation += 1;
+    DROPPED.store(0, Ordering::Relaxed);
+}
+
+impl Writer {
+    pub(crate) fn spawn(capacity: usize, policy: OverflowPolicy) -> Self {
+        open(capacity, policy);
+        let handle = thread::spawn(|| loop {
+            let (batch, open) = {
+                let mut state = STATE.lock().unwrap();
+                while state.open && state.events.is_empty() {
+                    state = PUSHED.wait(state).unwrap();
+                }
+                POPPED.notify_all();
+                (take(&mut state), state.open)
+            };
+            lock_instrumentor().write_queued(batch);
+            if !open {
+                return;
+            }
+        });
+        Writer { handle }
+    }
+
+    /// Stops taking spans, writes those still queued and waits for the
+    /// writer to exit. Must not be called with the instrumentor locked.
+    pub(crate) fn stop(self) {
+        drop(shut());
+        self.handle.join().unwrap();
+    }
+}
@@ -0,0 +1,525 @@
+//! Streaming reader for JSON trace files.
+//!
+//! Traces can be far larger than memory, so instead of parsing the whole
+//! document this walks it byte by byte and hands out one event object at a
+//! time, as raw JSON text. Everything around the event array is kept
+//! verbatim so rewritten traces keep their header and footer.
+
+use std::{
+    fmt,
+    io::{self, BufRead},
+    ops::Range,
+};
+
+use crate::{ArgValue, ProfileResult};
+
+pub(crate) struct TraceReader<R> {
+    reader: R,
+    prefix: String,
+    done: bool,
+}
+
+impl<R: BufRead> TraceReader<R> {
+    /// Reads everything up to and including the opening `[` of the event
+    /// array, either `traceEvents` of the object format or the top-level
+    /// array of the array format.
+    pub(crate) fn new(mut reader: R) -> io::Result<Self> {
+        let mut prefix = Vec::new();
+        let first = loop {
+            match next_byte(&mut reader)? {
+                Some(b) if b.is_ascii_whitespace() => prefix.push(b),
+                Some(b) => break b,
+                None => return Err(invalid("empty trace")),
+            }
+        };
+        prefix.push(first);
+        match first {
+            b'[' => {}
+            b'{' => read_until_events(&mut reader, &mut prefix)?,
+            _ => return Err(invalid("a trace must be a JSON object or array")),
+        }
+
+        Ok(TraceReader {
+            reader,
+            prefix: into_string(prefix)?,
+            done: false,
+        })
+    }
+
+    /// Continues reading in the middle of the event array, `reader` being
+    /// positioned between two events.
+    pub(crate) fn at_event(reader: R) -> Self {
+        TraceReader {
+            reader,
+            prefix: String::new(),
+            done: false,
+        }
+    }
+
+    pub(crate) fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The next event object, or `None` once the end of the array is reached.
+    pub(crate) fn next_event(&mut self) -> io::Result<Option<String>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        loop {
+            match next_byte(&mut self.reader)? {
+                Some(b) if b.is_ascii_whitespace() || b == b',' => {}
+                Some(b']') => {
+                    self.done = true;
+                    return Ok(None);
+                }
+                Some(b'{') => break,
+                Some(_) => return Err(invalid("trace events must be objects")),
+                None => return Err(invalid("unterminated event array")),
+            }
+        }
+
+        let mut event = vec![b'{'];
+        let mut scanner = Scanner {
+            depth: 1,
+            ..Scanner::default()
+        };
+        while scanner.depth > 0 {
+            let b = next_byte(&mut self.reader)?.ok_or_else(|| invalid("truncated event"))?;
+            event.push(b);
+            scanner.feed(b);
+        }
+        into_string(event).map(Some)
+    }
+
+    /// Everything from the closing `]` of the event array to the end of the
+    /// file. Only valid once [`TraceReader::next_event`] returned `None`.
+    pub(crate) fn into_suffix(mut self) -> io::Result<String> {
+        if !self.done {
+            return Err(invalid("the event array hasn't been read to the end"));
+        }
+        let mut rest = Vec::new();
+        self.reader.read_to_end(&mut rest)?;
+        Ok(format!("]{}", into_string(rest)?))
+    }
+}
+
+/// Why [`crate::Instrumentor::parse_session_bytes`] rejected a trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The trace ends before its footer, e.g. because the process crashed
+    /// during the session.
+    Truncated,
+    /// The header, the commas between the events or the footer aren't where
+    /// a session writes them.
+    Framing(String),
+    /// An event isn't valid JSON, or a span lacks one of its fields.
+    Json(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Truncated => write!(f, "the trace ends before its footer"),
+            ParseError::Framing(message) => write!(f, "bad framing: {message}"),
+            ParseError::Json(message) => write!(f, "bad event: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Reading the bytes of a session can't fail, the errors [`TraceReader`]
+/// returns for them are about the header.
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> Self {
+        ParseError::Framing(err.to_string())
+    }
+}
+
+impl From<ParseError> for io::Error {
+    fn from(err: ParseError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// The events of a JSON session, checking the framing more strictly than
+/// [`TraceReader`]: events separated by exactly one comma, no trailing comma,
+/// and nothing but the rest of the header object after the array.
+///
+/// With `allow_truncated`, a trace ending early, like one of a crashed
+/// process, isn't an error: the events read in full are returned, dropping
+/// a half written last one.
+pub(crate) fn session_events(
+    bytes: &[u8],
+    allow_truncated: bool,
+) -> Result<SessionEvents, ParseError> {
+    let framing = |message: &str| ParseError::Framing(message.to_string());
+    let mut reader = bytes;
+    let trace = TraceReader::new(&mut reader)?;
+    let prefix = trace.prefix().to_string();
+    if !prefix.trim_start().starts_with('{') {
+        return Err(framing("a session trace must be a JSON object"));
+    }
+    let mut session = SessionEvents {
+        prefix,
+        events: Vec::new(),
+        truncated: false,
+    };
+    let truncated = || {
+        if allow_truncated {
+            Ok(())
+        } else {
+            Err(ParseError::Truncated)
+        }
+    };
+
+    let mut expect_event = false;
+    loop {
+        match next_non_whitespace(&mut reader)? {
+            Some(b'{') if expect_event || session.events.is_empty() => {
+                let mut event = vec![b'{'];
+                let mut scanner = Scanner {
+                    depth: 1,
+                    ..Scanner::default()
+                };
+                while scanner.depth > 0 {
+                    match next_byte(&mut reader)? {
+                        Some(b) => {
+                            event.push(b);
+                            scanner.feed(b);
+                        }
+                        None => {
+                            truncated()?;
+                            session.truncated = true;
+                            return Ok(session);
+                        }
+                    }
+                }
+                let event = into_string(event).map_err(|err| ParseError::Json(err.to_string()))?;
+                session.events.push(event);
+                expect_event = false;
+            }
+            Some(b',') if !expect_event && !session.events.is_empty() => expect_event = true,
+            Some(b']') if !expect_event => break,
+            Some(b',') => return Err(framing("events must be separated by a single comma")),
+            Some(b']') => return Err(framing("trailing comma after the last event")),
+            Some(_) => return Err(framing("events must be separated by a single comma")),
+            None => {
+                truncated()?;
+                session.truncated = true;
+                return Ok(session);
+            }
+        }
+    }
+
+    let mut scanner = Scanner {
+        depth: 1,
+        ..Scanner::default()
+    };
+    while scanner.depth > 0 {
+        match next_byte(&mut reader)? {
+            Some(b) => scanner.feed(b),
+            None => {
+                truncated()?;
+                session.truncated = true;
+                return Ok(session);
+            }
+        }
+    }
+    if next_non_whitespace(&mut reader)?.is_some() {
+        return Err(framing("data after the end of the trace"));
+    }
+    Ok(session)
+}
+
+pub(crate) struct SessionEvents {
+    /// Everything up to and including the `[` opening the event array.
+    pub(crate) prefix: String,
+    pub(crate) events: Vec<String>,
+    /// Whether the trace ended before its footer.
+    pub(crate) truncated: bool,
+}
+
+/// The span written for the complete (`"ph":"X"`) event `event`, or `None`
+/// for the other events.
+pub(crate) fn span(event: &str) -> io::Result<Option<ProfileResult>> {
+    let fields = fields(event)?;
+    let get = |key: &str| fields.iter().find(|(k, _)| k == key).map(|(_, v)| *v);
+    if get("ph") != Some("\"X\"") {
+        return Ok(None);
+    }
+    let number = |key: &str| -> io::Result<i64> {
+        get(key)
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| invalid("span without an integer ts, dur or tid"))
+    };
+    let start = number("ts")?;
+    let args = match get("args") {
+        Some(args) => fields_to_args(args)?,
+        None => Vec::new(),
+    };
+    Ok(Some(ProfileResult {
+        name: string(get("name").ok_or_else(|| invalid("span without a name"))?)?,
+        start,
+        end: start.saturating_add(number("dur")?),
+        thread_id: number("tid")? as u32,
+        depth: 0,
+        args,
+        tags: Vec::new(),
+    }))
+}
+
+fn fields_to_args(args: &str) -> io::Result<Vec<(String, ArgValue)>> {
+    fields(args)?
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                "true" => ArgValue::Bool(true),
+                "false" => ArgValue::Bool(false),
+                _ if value.starts_with('"') => ArgValue::Str(string(value)?),
+                _ => match (value.parse::<i64>(), value.parse::<f64>()) {
+                    (Ok(int), _) => ArgValue::Int(int),
+                    (_, Ok(float)) => ArgValue::Float(float),
+                    // Nested values aren't written for spans, keep them as text.
+                    _ => ArgValue::Str(value.to_string()),
+                },
+            };
+            Ok((key, value))
+        })
+        .collect()
+}
+
+/// The raw JSON text of the top-level field `key` of the object `event`.
+pub(crate) fn field<'a>(event: &'a str, key: &str) -> Option<&'a str> {
+    field_range(event, key).map(|range| &event[range])
+}
+
+/// Where in `event` the value of its top-level field `key` is, without the
+/// surrounding whitespace.
+pub(crate) fn field_range(event: &str, key: &str) -> Option<Range<usize>> {
+    let trimmed = |start: usize, end: usize| {
+        let value = &event[start..end];
+        let start = start + (value.len() - value.trim_start().len());
+        start..start + value.trim().len()
+    };
+    let bytes = event.as_bytes();
+    let mut scanner = Scanner::default();
+    let mut string_start = 0;
+    let mut last_key = None;
+    let mut value_start = None;
+
+    for (i, &b) in bytes.iter().enumerate() {
+        let was_in_string = scanner.in_string;
+        let depth = scanner.depth;
+        scanner.feed(b);
+
+        if depth != 1 {
+            continue;
+        }
+        if !was_in_string && scanner.in_string {
+            string_start = i + 1;
+        } else if was_in_string && !scanner.in_string {
+            if value_start.is_none() {
+                last_key = Some(&event[string_start..i]);
+            }
+        } else if !scanner.in_string {
+            match b {
+                b':' => value_start = Some(i + 1),
+                b',' => {
+                    if let (Some(start), Some(k)) = (value_start, last_key) {
+                        if k == key {
+                            return Some(trimmed(start, i));
+                        }
+                    }
+                    value_start = None;
+                    last_key = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // The last field is terminated by the object's closing brace.
+    match (value_start, last_key) {
+        (Some(start), Some(k)) if k == key => {
+            let end = event.trim_end().strip_suffix('}')?.len();
+            Some(trimmed(start, end))
+        }
+        _ => None,
+    }
+}
+
+/// The top-level fields of the object `object`, as unescaped keys and raw
+/// JSON values.
+pub(crate) fn fields(object: &str) -> io::Result<Vec<(String, &str)>> {
+    let body = object
+        .trim()
+        .strip_prefix('{')
+        .and_then(|body| body.strip_suffix('}'))
+        .ok_or_else(|| invalid("not a JSON object"))?;
+    let mut fields = Vec::new();
+    let mut scanner = Scanner::default();
+    let mut start = 0;
+    for (i, b) in body.bytes().chain(Some(b',')).enumerate() {
+        if b == b',' && scanner.depth == 0 && !scanner.in_string {
+            let field = &body[start..i];
+            start = i + 1;
+            if field.trim().is_empty() && fields.is_empty() && i == body.len() {
+                break;
+            }
+            let colon = field_colon(field).ok_or_else(|| invalid("malformed field"))?;
+            fields.push((string(&field[..colon])?, field[colon + 1..].trim()));
+            continue;
+        }
+        scanner.feed(b);
+    }
+    Ok(fields)
+}
+
+/// Where the `:` after the key of `field` is.
+fn field_colon(field: &str) -> Option<usize> {
+    let mut scanner = Scanner::default();
+    for (i, b) in field.bytes().enumerate() {
+        if b == b':' && !scanner.in_string {
+            return Some(i);
+        }
+        scanner.feed(b);
+    }
+    None
+}
+
+/// The value of the raw JSON string `raw`.
+pub(crate) fn string(raw: &str) -> io::Result<String> {
+    let inner = raw
+        .trim()
+        .strip_prefix('"')
+        .and_then(|inner| inner.strip_suffix('"'))
+        .ok_or_else(|| invalid("not a JSON string"))?;
+    let mut value = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => value.push('\n'),
+            Some('r') => value.push('\r'),
+            Some('t') => value.push('\t'),
+            Some('b') => value.push('\u{8}'),
+            Some('f') => value.push('\u{c}'),
+            Some('u') => {
+                let hex: String = chars.by_ref().take(4).collect();
+                let mut code = u32::from_str_radix(&hex, 16).map_err(|_| invalid("bad escape"))?;
+                // A surrogate pair is two escapes.
+                if (0xd800..0xdc00).contains(&code) {
+                    let low: String = chars.by_ref().take(6).collect();
+                    let low = low
+                        .strip_prefix("\\u")
+                        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                        .ok_or_else(|| invalid("bad escape"))?;
+                    code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                }
+                value.push(char::from_u32(code).ok_or_else(|| invalid("bad escape"))?);
+            }
+            Some(c) => value.push(c),
+            None => return Err(invalid("bad escape")),
+        }
+    }
+    Ok(value)
+}
+
+/// Tracks nesting depth and strings while walking JSON text.
+#[derive(Default)]
+struct Scanner {
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl Scanner {
+    fn feed(&mut self, b: u8) {
+        if self.in_string {
+            match b {
+                _ if self.escaped => self.escaped = false,
+                b'\\' => self.escaped = true,
+                b'"' => self.in_string = false,
+                _ => {}
+            }
+            return;
+        }
+        match b {
+            b'"' => self.in_string = true,
+            b'{' | b'[' => self.depth += 1,
+            b'}' | b']' => self.depth = self.depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+}
+
+/// Reads the rest of the object format header, up to the `[` opening the
+/// `traceEvents` array.
+fn read_until_events(reader: &mut impl BufRead, prefix: &mut Vec<u8>) -> io::Result<()> {
+    let mut scanner = Scanner {
+        depth: 1,
+        ..Scanner::default()
+    };
+    let mut string = Vec::new();
+    let mut last_string = Vec::new();
+    let mut key = Vec::new();
+
+    loop {
+        let b = next_byte(reader)?.ok_or_else(|| invalid("no traceEvents array"))?;
+        prefix.push(b);
+        let was_in_string = scanner.in_string;
+        let depth = scanner.depth;
+
+        if depth == 1 && !was_in_string {
+            match b {
+                b':' => key = std::mem::take(&mut last_string),
+                b',' => key.clear(),
+                b'[' if key == b"traceEvents" => return Ok(()),
+                _ => {}
+            }
+        }
+
+        scanner.feed(b);
+        if depth == 1 && was_in_string {
+            if scanner.in_string {
+                string.push(b);
+            } else {
+                last_string = std::mem::take(&mut string);
+            }
+        }
+        if scanner.depth == 0 {
+            return Err(invalid("no traceEvents array"));
+        }
+    }
+}
+
+fn next_non_whitespace(reader: &mut impl BufRead) -> io::Result<Option<u8>> {
+    loop {
+        match next_byte(reader)? {
+            Some(b) if b.is_ascii_whitespace() => {}
+            b => return Ok(b),
+        }
+    }
+}
+
+fn next_byte(reader: &mut impl BufRead) -> io::Result<Option<u8>> {
+    let b = match reader.fill_buf()?.first() {
+        Some(&b) => b,
+        None => return Ok(None),
+    };
+    reader.consume(1);
+    Ok(Some(b))
+}
+
+fn into_string(bytes: Vec<u8>) -> io::Result<String> {
+    String::from_utf8(bytes).map_err(|_| invalid("trace isn't valid UTF-8"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,236 @@
+//! Tools rewriting existing trace files.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    ops::Range,
+    path::Path,
+};
+
+use crate::{
+    escape_json,
+    reader::{self, TraceReader},
+    write_chrome_header, SCHEMA_VERSION,
+};
+
+/// Copies the trace at `in_path` to `out_path`, keeping only the events
+/// `keep` returns true for. The header and footer are copied as they are.
+fn filter_trace(
+    in_path: &str,
+    out_path: &str,
+    mut keep: impl FnMut(&str) -> bool,
+) -> io::Result<()> {
+    let mut trace = TraceReader::new(BufReader::new(File::open(in_path)?))?;
+    let mut out = BufWriter::new(File::create(out_path)?);
+
+    out.write_all(trace.prefix().as_bytes())?;
+    let mut first = true;
+    while let Some(event) = trace.next_event()? {
+        if !keep(&event) {
+            continue;
+        }
+        if !first {
+            out.write_all(b",")?;
+        }
+        out.write_all(event.as_bytes())?;
+        first = false;
+    }
+    out.write_all(trace.into_suffix()?.as_bytes())?;
+    out.flush()
+}
+
+/// Drops the spans shorter than `min_duration` microseconds. Events without a
+/// duration, like thread names and samples, are kept.
+pub(crate) fn downsample_trace(in_path: &str, out_path: &str, min_duration: i64) -> io::Result<()> {
+    filter_trace(in_path, out_path, |event| {
+        match reader::field(event, "dur").and_then(|dur| dur.parse::<f64>().ok()) {
+            Some(dur) => dur >= min_duration as f64,
+            None => true,
+        }
+    })
+}
+
+/// See [`crate::Instrumentor::rebase_trace`].
+pub(crate) fn rebase_trace(in_path: &str, out_path: &str) -> io::Result<()> {
+    let mut trace = TraceReader::new(BufReader::new(File::open(in_path)?))?;
+    let mut min_ts: Option<i64> = None;
+    while let Some(event) = trace.next_event()? {
+        if let Some(ts) = reader::field(&event, "ts").and_then(|ts| ts.parse().ok()) {
+            min_ts = Some(min_ts.map_or(ts, |min: i64| min.min(ts)));
+        }
+    }
+    let shift = min_ts.unwrap_or(0);
+
+    let mut trace = TraceReader::new(BufReader::new(File::open(in_path)?))?;
+    let mut out = BufWriter::new(File::create(out_path)?);
+    let mut prefix = trace.prefix().to_string();
+    if let Some(range) = header_number_range(&prefix, "unixMicros") {
+        if let Ok(unix_micros) = prefix[range.clone()].parse::<i64>() {
+            prefix.replace_range(range, &unix_micros.saturating_add(shift).to_string());
+        }
+    }
+    out.write_all(prefix.as_bytes())?;
+    let mut first = true;
+    while let Some(mut event) = trace.next_event()? {
+        if let Some(range) = reader::field_range(&event, "ts") {
+            if let Ok(ts) = event[range.clone()].parse::<i64>() {
+                event.replace_range(range, &ts.saturating_sub(shift).to_string());
+            }
+        }
+        if !first {
+            out.write_all(b",")?;
+        }
+        out.write_all(event.as_bytes())?;
+        first = false;
+    }
+    out.write_all(trace.into_suffix()?.as_bytes())?;
+    out.flush()
+}
+
+/// See [`crate::Instrumentor::split_trace_by_thread`].
+pub(crate) fn split_trace_by_thread(in_path: &str, out_dir: &str) -> io::Result<Vec<String>> {
+    let mut trace = TraceReader::new(BufReader::new(File::open(in_path)?))?;
+    std::fs::create_dir_all(out_dir)?;
+
+    let mut outputs: BTreeMap<(i64, i64), (String, BufWriter<File>)> = BTreeMap::new();
+    // Events not on any thread, like process names, go to every output,
+    // including the ones opened later.
+    let mut shared: Vec<String> = Vec::new();
+    while let Some(event) = trace.next_event()? {
+        let Some(tid) = reader::field(&event, "tid") else {
+            for (_, out) in outputs.values_mut() {
+                out.write_all(b",")?;
+                out.write_all(event.as_bytes())?;
+            }
+            shared.push(event);
+            continue;
+        };
+        // The ids end up in file names, so only numbers are accepted.
+        let id = |raw: &str| raw.trim_matches('"').parse::<i64>().ok();
+        let tid = id(tid)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "tid isn't a number"))?;
+        let pid = match reader::field(&event, "pid") {
+            Some(pid) => id(pid)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "pid isn't a number"))?,
+            None => 0,
+        };
+        let (_, out) = match outputs.get_mut(&(pid, tid)) {
+            Some(output) => {
+                output.1.write_all(b",")?;
+                output
+            }
+            None => {
+                let file_name = format!("{pid}-{tid}.json");
+                let path = Path::new(out_dir).join(file_name);
+                let path = path.to_string_lossy().into_owned();
+                let mut out = BufWriter::new(File::create(&path)?);
+                out.write_all(trace.prefix().as_bytes())?;
+                for event in &shared {
+                    out.write_all(event.as_bytes())?;
+                    out.write_all(b",")?;
+                }
+                outputs.entry((pid, tid)).or_insert((path, out))
+            }
+        };
+        out.write_all(event.as_bytes())?;
+    }
+
+    let suffix = trace.into_suffix()?;
+    let mut paths = Vec::new();
+    for (path, mut out) in outputs.into_values() {
+        out.write_all(suffix.as_bytes())?;
+        out.flush()?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// See [`crate::Instrumentor::merge_traces`].
+pub(crate) fn merge_traces(in_paths: &[&str], out_path: &str) -> io::Result<()> {
+    let mut traces = Vec::new();
+    for path in in_paths {
+        let trace = TraceReader::new(BufReader::new(File::open(path)?))?;
+        let unix_micros = header_number(trace.prefix(), "unixMicros");
+        traces.push((path, trace, unix_micros));
+    }
+    let base = traces
+        .iter()
+        .filter_map(|(_, _, unix_micros)| *unix_micros)
+        .min();
+
+    let mut out = BufWriter::new(File::create(out_path)?);
+    write_chrome_header(&mut out, SCHEMA_VERSION, base, false)?;
+    let mut first = true;
+    for (pid, (path, mut trace, unix_micros)) in traces.into_iter().enumerate() {
+        let shift = unix_micros
+            .zip(base)
+            .map_or(0, |(micros, base)| micros.saturating_sub(base));
+        if !first {
+            out.write_all(b",")?;
+        }
+        first = false;
+        write!(
+            out,
+            "{{\"args\":{{\"name\":\"{}\"}},\"cat\":\"__metadata\",\"name\":\"process_name\",\"ph\":\"M\",\"pid\":{},\"tid\":0}}",
+            escape_json(path),
+            pid,
+        )?;
+
+        while let Some(mut event) = trace.next_event()? {
+            if reader::field(&event, "ph") == Some("\"P\"") {
+                continue;
+            }
+            if let Some(range) = reader::field_range(&event, "ts") {
+                if let Ok(ts) = event[range.clone()].parse::<i64>() {
+                    event.replace_range(range, &ts.saturating_add(shift).to_string());
+                }
+            }
+            if let Some(range) = reader::field_range(&event, "pid") {
+                event.replace_range(range, &pid.to_string());
+            }
+            out.write_all(b",")?;
+            out.write_all(event.as_bytes())?;
+        }
+    }
+    out.write_all(b"]}")?;
+    out.flush()
+}
+
+/// See [`crate::Instrumentor::repair_trace`].
+pub(crate) fn repair_trace(in_path: &str, out_path: &str) -> io::Result<bool> {
+    let bytes = std::fs::read(in_path)?;
+    let session = reader::session_events(&bytes, true)?;
+    if !session.truncated {
+        std::fs::write(out_path, &bytes)?;
+        return Ok(false);
+    }
+
+    let mut out = BufWriter::new(File::create(out_path)?);
+    out.write_all(session.prefix.as_bytes())?;
+    for (i, event) in session.events.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        out.write_all(event.as_bytes())?;
+    }
+    out.write_all(b"]}")?;
+    out.flush()?;
+    Ok(true)
+}
+
+/// The number following `"key":` in a trace header, which unlike events
+/// isn't a complete object that [`reader::field`] could look into.
+fn header_number(header: &str, key: &str) -> Option<i64> {
+    header[header_number_range(header, key)?].parse().ok()
+}
+
+fn header_number_range(header: &str, key: &str) -> Option<Range<usize>> {
+    let after_key = header.find(&format!("\"{key}\":"))? + key.len() + 3;
+    let rest = header[after_key..].trim_start();
+    let start = header.len() - rest.len();
+    let len = rest
+        .find(|c: char| !(c == '-' || c.is_ascii_digit()))
+        .unwrap_or(rest.len());
+    Some(start..start + len)
+}
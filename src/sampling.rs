@@ -0,0 +1,96 @@
+//! Periodic sampling of the spans open on every thread.
+//!
+//! While a sampler is running, timers push their name onto a per-thread
+//! stack that the sampler thread can read. The stacks are only maintained
+//! while sampling is on so plain timers don't pay for it.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex, Weak,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{current_thread_id, Instrumentor};
+
+type SpanStack = Arc<Mutex<Vec<String>>>;
+
+/// A thread's id and its span stack.
+type RegisteredStack = (u32, Weak<Mutex<Vec<String>>>);
+
+static SAMPLING: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    /// Span stacks of every thread that has recorded a span while sampling,
+    /// pruned once their thread exits.
+    static ref SPAN_STACKS: Mutex<Vec<RegisteredStack>> = Mutex::new(Vec::new());
+}
+
+thread_local! {
+    static SPAN_STACK: SpanStack = {
+        let stack = SpanStack::default();
+        SPAN_STACKS
+            .lock()
+            .unwrap()
+            .push((current_thread_id(), Arc::downgrade(&stack)));
+        stack
+    };
+}
+
+/// Records that a span called `name` was entered on this thread. Returns
+/// whether it was pushed, in which case [`pop`] must be called when it ends.
+pub(crate) fn push(name: &str) -> bool {
+    if !SAMPLING.load(Ordering::Relaxed) {
+        return false;
+    }
+    SPAN_STACK.with(|stack| stack.lock().unwrap().push(name.to_string()));
+    true
+}
+
+pub(crate) fn pop() {
+    SPAN_STACK.with(|stack| stack.lock().unwrap().pop());
+}
+
+/// The open spans of every live thread, outermost first.
+fn snapshot() -> Vec<(u32, Vec<String>)> {
+    let mut stacks = SPAN_STACKS.lock().unwrap();
+    stacks.retain(|(_, stack)| stack.strong_count() > 0);
+    stacks
+        .iter()
+        .filter_map(|(thread_id, stack)| {
+            let stack = stack.upgrade()?.lock().unwrap().clone();
+            (!stack.is_empty()).then_some((*thread_id, stack))
+        })
+        .collect()
+}
+
+/// A background thread writing a sample of every thread's span stack once per
+/// interval.
+pub(crate) struct Sampler {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Sampler {
+    pub(crate) fn spawn(interval: Duration) -> Self {
+        SAMPLING.store(true, Ordering::Relaxed);
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                Instrumentor::write_samples(snapshot());
+            }
+        });
+        Sampler { stop, handle }
+    }
+
+    /// Stops the sampler and waits for its thread to exit. Must not be called
+    /// with the instrumentor locked, the sampler may be waiting on it.
+    pub(crate) fn stop(self) {
+        SAMPLING.store(false, Ordering::Relaxed);
+        drop(self.stop);
+        self.handle.join().unwrap();
+    }
+}
@@ -0,0 +1,37 @@
+//! Scheduling parameters of the calling thread, recorded with its name.
+//!
+//! Read from `/proc/thread-self`, so they need no bindings to the
+//! `sched_getaffinity` and `getpriority` syscalls the kernel fills them from.
+
+use std::fs;
+
+use crate::ArgValue;
+
+/// The `nice` value and the `affinity` CPU list, e.g. `"0-3,6"`, of the
+/// calling thread. Either is left out if it can't be read.
+pub(crate) fn thread_args() -> Vec<(String, ArgValue)> {
+    let mut args = Vec::new();
+    if let Some(affinity) = fs::read_to_string("/proc/thread-self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Cpus_allowed_list:"))
+                .map(|list| list.trim().to_string())
+        })
+    {
+        args.push(("affinity".to_string(), ArgValue::Str(affinity)));
+    }
+    // The command name in parentheses can contain spaces, the fields after
+    // it can't. `nice` is the 19th field, the 17th after the name.
+    if let Some(nice) = fs::read_to_string("/proc/thread-self/stat")
+        .ok()
+        .and_then(|stat| {
+            let fields = &stat[stat.rfind(')')? + 1..];
+            fields.split_whitespace().nth(16)?.parse::<i64>().ok()
+        })
+    {
+        args.push(("nice".to_string(), ArgValue::Int(nice)));
+    }
+    args
+}
@@ -0,0 +1,35 @@
+//! The layout of the JSON events this crate writes, for tools reading its
+//! traces to check them against. Covers the version in [`SCHEMA_VERSION`],
+//! and changes along with the writers.
+//!
+//! [`SCHEMA_VERSION`]: crate::SCHEMA_VERSION
+
+/// The phases (`"ph"`) of the events written, apart from the events of
+/// [`crate::Instrumentor::write_raw_event`].
+pub const PHASES: &[char] = &['X', 'M', 'C', 'P', 'i', 'b', 'e'];
+
+/// Spans.
+const COMPLETE: &[&str] = &["args", "cat", "dur", "name", "ph", "pid", "tid", "ts"];
+/// Thread and process names.
+const METADATA: &[&str] = &["args", "cat", "name", "ph", "pid", "tid"];
+const COUNTER: &[&str] = &["args", "cat", "name", "ph", "pid", "ts"];
+const SAMPLE: &[&str] = &["cat", "name", "ph", "pid", "sf", "tid", "ts"];
+/// Session markers.
+const INSTANT: &[&str] = &["args", "cat", "name", "ph", "pid", "s", "tid", "ts"];
+const ASYNC: &[&str] = &["cat", "id", "id2", "name", "ph", "pid", "tid", "ts"];
+
+/// The fields events of `phase` may have, in the order they're written.
+/// Spans only have `args` if they have args or tags, and async events have
+/// either `id` or `id2`, depending on their [`crate::AsyncId`]. Empty for
+/// phases that aren't written.
+pub fn chrome_fields(phase: char) -> &'static [&'static str] {
+    match phase {
+        'X' => COMPLETE,
+        'M' => METADATA,
+        'C' => COUNTER,
+        'P' => SAMPLE,
+        'i' => INSTANT,
+        'b' | 'e' => ASYNC,
+        _ => &[],
+    }
+}
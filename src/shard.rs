@@ -0,0 +1,183 @@
+//! Per-thread output files for sharded sessions.
+//!
+//! Every thread recording a span in a sharded session gets its own temporary
+//! file, so timers never wait on each other. The only lock a timer takes is
+//! its own shard's, which is contended just once, when the session ends and
+//! the shards are merged. Registering a new shard takes the registry lock,
+//! once per thread and session.
+
+use std::{
+    cell::RefCell,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use crate::{filtered_name, session_span, write_thread_name_json, ArgValue, ProfileResult};
+
+/// Generation of the active sharded session, 0 while there's none.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+static SESSION: Mutex<Option<ShardedSession>> = Mutex::new(None);
+
+type SharedShard = Arc<Mutex<Option<Shard>>>;
+
+/// A thread's shard with the start of its session.
+type OpenShard = (Instant, SharedShard);
+
+struct ShardedSession {
+    generation: u64,
+    start: Instant,
+    shards: Vec<SharedShard>,
+}
+
+struct Shard {
+    out: BufWriter<File>,
+    path: PathBuf,
+    first_event: bool,
+    error: Option<io::Error>,
+}
+
+/// A closed shard, to be merged into the trace.
+pub(crate) struct ShardFile {
+    pub(crate) path: PathBuf,
+    pub(crate) error: Option<io::Error>,
+}
+
+thread_local! {
+    /// The generation of this thread's last sharded session, with the
+    /// session's start and the thread's shard, or `None` if that couldn't be
+    /// opened. The failure is kept so it isn't retried for every span.
+    static SHARD: RefCell<Option<(u64, Option<OpenShard>)>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn begin(start: Instant) {
+    let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+    *SESSION.lock().unwrap() = Some(ShardedSession {
+        generation,
+        start,
+        shards: Vec::new(),
+    });
+    GENERATION.store(generation, Ordering::Release);
+}
+
+/// Closes every shard of the active session. Spans stopped from now on are
+/// no longer written to a shard.
+pub(crate) fn end() -> Vec<ShardFile> {
+    GENERATION.store(0, Ordering::Release);
+    let Some(session) = SESSION.lock().unwrap().take() else {
+        return Vec::new();
+    };
+    session
+        .shards
+        .iter()
+        .filter_map(|shard| shard.lock().unwrap().take())
+        .map(|mut shard| {
+            if let Err(err) = shard.out.flush() {
+                shard.error.get_or_insert(err);
+            }
+            ShardFile {
+                path: shard.path,
+                error: shard.error,
+            }
+        })
+        .collect()
+}
+
+/// Writes a span to the calling thread's shard. Hands `args` back if no
+/// sharded session is active, for the span to be recorded the usual way.
+pub(crate) fn record(
+    name: &str,
+    start_timepoint: Instant,
+    duration: i64,
+    thread_id: u32,
+    depth: u32,
+    args: Vec<(String, ArgValue)>,
+    tags: &[&'static str],
+) -> Result<(), Vec<(String, ArgValue)>> {
+    let generation = GENERATION.load(Ordering::Acquire);
+    if generation == 0 {
+        return Err(args);
+    }
+
+    SHARD.with_borrow_mut(|cached| {
+        if cached.as_ref().is_none_or(|(g, _)| *g != generation) {
+            *cached = Some((generation, open(generation)));
+        }
+        let Some((_, Some((session_start, shard)))) = cached.as_ref() else {
+            return Err(args);
+        };
+        let mut shard = shard.lock().unwrap();
+        let Some(shard) = shard.as_mut().filter(|shard| shard.error.is_none()) else {
+            return Err(args);
+        };
+
+        let (start, end) = session_span(*session_start, start_timepoint, duration);
+        let result = ProfileResult {
+            name: filtered_name(name).into_owned(),
+            start,
+            end,
+            thread_id,
+            depth,
+            args,
+            tags: tags.to_vec(),
+        };
+        if let Err(err) = shard.write(&result) {
+            shard.error = Some(err);
+        }
+        Ok(())
+    })
+}
+
+/// Registers a shard for the calling thread with the session of
+/// `generation`, unless that session has ended in the meantime, and returns
+/// it with the session's start.
+fn open(generation: u64) -> Option<OpenShard> {
+    let mut session = SESSION.lock().unwrap();
+    let session = session
+        .as_mut()
+        .filter(|session| session.generation == generation)?;
+
+    let path = std::env::temp_dir().join(format!(
+        "simple-tracing-{}-{}-{}.json",
+        std::process::id(),
+        generation,
+        session.shards.len(),
+    ));
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .ok()?;
+    let shard = Arc::new(Mutex::new(Some(Shard {
+        out: BufWriter::new(file),
+        path,
+        first_event: true,
+        error: None,
+    })));
+    session.shards.push(shard.clone());
+    Some((session.start, shard))
+}
+
+impl Shard {
+    /// Shards hold comma separated events, the first one preceded by the
+    /// thread's name.
+    fn write(&mut self, result: &ProfileResult) -> io::Result<()> {
+        if self.first_event {
+            write_thread_name_json(
+                &mut self.out,
+                result.thread_id,
+                &crate::current_thread_name(result.thread_id),
+                &crate::current_thread_metadata(),
+            )?;
+            self.first_event = false;
+        }
+        write!(self.out, ",")?;
+        result.write_chrome_json(&mut self.out, "function")
+    }
+}
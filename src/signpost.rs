@@ -0,0 +1,110 @@
+//! Span intervals for macOS' `os_signpost`.
+//!
+//! Spans are signpost intervals named `span` in the log of subsystem
+//! `simple-tracing`, category `spans`, with the span's name as the begin
+//! message. Instruments shows them in the os_signpost instrument next to the
+//! system's own signposts.
+
+use std::{
+    ffi::{c_char, c_void, CStr, CString},
+    io, ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+extern "C" {
+    fn os_log_create(subsystem: *const c_char, category: *const c_char) -> *mut c_void;
+    fn os_signpost_enabled(log: *mut c_void) -> bool;
+    fn os_signpost_id_generate(log: *mut c_void) -> u64;
+    /// What the `os_signpost_interval_begin` and `_end` macros expand to.
+    fn _os_signpost_emit_with_name_impl(
+        dso: *const c_void,
+        log: *mut c_void,
+        kind: u8,
+        id: u64,
+        name: *const c_char,
+        format: *const c_char,
+        buf: *mut u8,
+        size: u32,
+    );
+    /// The image the name and format strings are looked up in.
+    #[allow(non_upper_case_globals)]
+    static __dso_handle: c_void;
+}
+
+const INTERVAL_BEGIN: u8 = 1;
+const INTERVAL_END: u8 = 2;
+/// Both have to be literals in this image.
+const NAME: &CStr = c"span";
+const BEGIN_FORMAT: &CStr = c"%{public}s";
+
+/// The `os_log_t` spans are written to, null while disabled. Never released,
+/// like logs usually aren't.
+static LOG: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+/// An interval begun by [`begin`], ended in the log it was begun in even if
+/// signposts were disabled or re-enabled since.
+pub(crate) struct Interval {
+    log: *mut c_void,
+    id: u64,
+}
+
+// SAFETY: `os_log_t`s can be used from any thread, and logs are never
+// released.
+unsafe impl Send for Interval {}
+
+pub(crate) fn enable() -> io::Result<()> {
+    if !LOG.load(Ordering::Relaxed).is_null() {
+        return Ok(());
+    }
+    // SAFETY: both strings are NUL-terminated.
+    let log = unsafe { os_log_create(c"simple-tracing".as_ptr(), c"spans".as_ptr()) };
+    if log.is_null() {
+        return Err(io::Error::other("os_log_create failed"));
+    }
+    LOG.store(log, Ordering::Relaxed);
+    Ok(())
+}
+
+pub(crate) fn disable() {
+    LOG.store(ptr::null_mut(), Ordering::Relaxed);
+}
+
+/// `None` while disabled or while nothing records the log.
+pub(crate) fn begin(name: &str) -> Option<Interval> {
+    let log = LOG.load(Ordering::Relaxed);
+    // SAFETY: `log` came from `os_log_create`.
+    if log.is_null() || !unsafe { os_signpost_enabled(log) } {
+        return None;
+    }
+    // SAFETY: as above.
+    let id = unsafe { os_signpost_id_generate(log) };
+    let name = CString::new(name.replace('\0', "")).unwrap();
+    // One public string argument: the summary byte flags non-scalar
+    // arguments, then the argument's type and flags, size and pointer.
+    let mut buf = [0u8; 12];
+    buf[..4].copy_from_slice(&[0x02, 1, 0x22, 8]);
+    buf[4..].copy_from_slice(&(name.as_ptr() as u64).to_ne_bytes());
+    emit(log, INTERVAL_BEGIN, id, BEGIN_FORMAT, &mut buf);
+    Some(Interval { log, id })
+}
+
+pub(crate) fn end(interval: Interval) {
+    emit(interval.log, INTERVAL_END, interval.id, c"", &mut [0, 0]);
+}
+
+fn emit(log: *mut c_void, kind: u8, id: u64, format: &'static CStr, buf: &mut [u8]) {
+    // SAFETY: `log` came from `os_log_create`, the strings are literals of
+    // this image and `buf` encodes the arguments `format` takes.
+    unsafe {
+        _os_signpost_emit_with_name_impl(
+            ptr::addr_of!(__dso_handle),
+            log,
+            kind,
+            id,
+            NAME.as_ptr(),
+            format.as_ptr(),
+            buf.as_mut_ptr(),
+            buf.len() as u32,
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! Extra outputs fed alongside a session's own, e.g. a filtered live feed to
+//! a socket while the full trace goes to a file.
+
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use crate::{write_chrome_header, ProfileResult, RecordPredicate, SessionObserver, SCHEMA_VERSION};
+
+/// Writes the spans of every session as a JSON trace of its own to a
+/// writer, with its own header, footer and optional filter. Added with
+/// [`crate::Instrumentor::add_observer`], any number of them can run next to
+/// the session's output.
+///
+/// Each span is flushed as it's written, so a remote viewer sees it right
+/// away. A failing write stops this sink until the next session and is kept
+/// in its [`SinkError`], the session and the other sinks carry on.
+///
+/// Like every observer, a sink writes and flushes with the instrumentor
+/// locked: every thread ending a span waits for it, and for every other sink.
+/// It has no buffer or thread of its own, so a writer that can block, like a
+/// socket to a slow viewer, should hand the bytes off, e.g. to a channel
+/// drained by another thread, rather than writing them out itself.
+pub struct JsonSink<W> {
+    out: W,
+    filter: Option<RecordPredicate>,
+    first_event: bool,
+    error: SinkError,
+}
+
+impl<W: Write + Send> JsonSink<W> {
+    pub fn new(out: W) -> Self {
+        JsonSink {
+            out,
+            filter: None,
+            first_event: true,
+            error: SinkError::default(),
+        }
+    }
+
+    /// Only writes the spans `filter` returns true for, by name.
+    pub fn with_filter(mut self, filter: RecordPredicate) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// A handle on this sink's errors, still usable once the sink was added.
+    pub fn error(&self) -> SinkError {
+        self.error.clone()
+    }
+
+    fn write(&mut self, write: impl FnOnce(&mut W) -> io::Result<()>) {
+        if self.error.is_set() {
+            return;
+        }
+        if let Err(err) = write(&mut self.out).and_then(|()| self.out.flush()) {
+            *self.error.0.lock().unwrap() = Some(err);
+        }
+    }
+}
+
+impl<W: Write + Send> SessionObserver for JsonSink<W> {
+    fn on_begin(&mut self, _name: &str) {
+        *self.error.0.lock().unwrap() = None;
+        self.first_event = true;
+        self.write(|out| write_chrome_header(out, SCHEMA_VERSION, None, false));
+    }
+
+    fn on_event(&mut self, result: &ProfileResult) {
+        if self
+            .filter
+            .as_ref()
+            .is_some_and(|filter| !filter(&result.name))
+        {
+            return;
+        }
+        let first_event = std::mem::replace(&mut self.first_event, false);
+        self.write(|out| {
+            if !first_event {
+                write!(out, ",")?;
+            }
+            result.write_chrome_json(out, "function")
+        });
+    }
+
+    fn on_end(&mut self) {
+        self.write(|out| write!(out, "]}}"));
+    }
+}
+
+/// The error that stopped a [`JsonSink`] during the current or last session.
+#[derive(Clone, Default)]
+pub struct SinkError(Arc<Mutex<Option<io::Error>>>);
+
+impl SinkError {
+    pub fn get(&self) -> Option<io::Error> {
+        self.0
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|err| io::Error::new(err.kind(), err.to_string()))
+    }
+
+    fn is_set(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+}
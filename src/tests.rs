@@ -0,0 +1,2450 @@
+//! Tests of whole sessions. They share the global instrumentor, so each one
+//! holds [`serial`] and puts back any setting it changes.
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::*;
+
+fn serial() -> MutexGuard<'static, ()> {
+    static SERIAL: Mutex<()> = Mutex::new(());
+    SERIAL.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A fresh path in the temp directory, unique to the test process.
+fn temp_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("simple-tracing-{}-{name}", std::process::id()));
+    path.to_string_lossy().into_owned()
+}
+
+/// Runs `record` in a session writing to a temp file and returns the file.
+fn record_session(name: &str, record: impl FnOnce()) -> Vec<u8> {
+    let path = temp_path(name);
+    Instrumentor::begin_session(name, &path);
+    record();
+    Instrumentor::end_session();
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    bytes
+}
+
+const AWKWARD_NAME: &str = "C:\\Users\\me \"quoted\"\nsecond line";
+
+#[test]
+fn names_round_trip_through_the_reader() {
+    let _serial = serial();
+    let bytes = record_session("names", || {
+        InstrumentationTimer::new(AWKWARD_NAME);
+    });
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].name, AWKWARD_NAME);
+}
+
+#[test]
+fn trace_names_round_trip_through_the_reader() {
+    let mut trace = Trace::new(AWKWARD_NAME);
+    trace.push(ProfileResult {
+        name: AWKWARD_NAME.to_string(),
+        start: 1,
+        end: 2,
+        thread_id: 1,
+        depth: 0,
+        args: Vec::new(),
+        tags: Vec::new(),
+    });
+    let spans = Instrumentor::parse_session_bytes(trace.to_chrome_string().as_bytes()).unwrap();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].name, AWKWARD_NAME);
+}
+
+/// A filter panicking with the instrumentor locked mustn't poison it for the
+/// timers still running, nor abort when it panics again while unwinding.
+#[test]
+fn panicking_name_filter_leaves_the_instrumentor_usable() {
+    let _serial = serial();
+    Instrumentor::set_name_filter(Box::new(|name| {
+        if name == "boom" {
+            panic!("filter panicked");
+        }
+        Cow::Borrowed(name)
+    }));
+    let bytes = record_session("filter-panic", || {
+        let _outer = InstrumentationTimer::new("outer");
+        let caught = std::panic::catch_unwind(|| {
+            InstrumentationTimer::new("boom");
+        });
+        assert!(caught.is_err());
+        let caught = std::panic::catch_unwind(|| {
+            let _unwinding = InstrumentationTimer::new("boom");
+            panic!("body panicked");
+        });
+        assert!(caught.is_err());
+    });
+    Instrumentor::clear_name_filter();
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    let names: Vec<&str> = spans.iter().map(|span| span.name.as_str()).collect();
+    assert!(names.contains(&"outer"), "{names:?}");
+}
+
+#[test]
+fn flushed_trace_parses_while_the_session_runs() {
+    let _serial = serial();
+    const SPANS: usize = 25;
+    let path = temp_path("flush");
+    Instrumentor::begin_session("flush", &path);
+    for _ in 0..SPANS {
+        InstrumentationTimer::new("span");
+    }
+    Instrumentor::flush();
+    let mut bytes = std::fs::read(&path).unwrap();
+    Instrumentor::end_session();
+    std::fs::remove_file(&path).unwrap();
+
+    bytes.extend_from_slice(b"]}");
+    let trace: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let spans = trace["traceEvents"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|event| event["ph"] == "X")
+        .count();
+    assert_eq!(spans, SPANS);
+    assert_eq!(
+        Instrumentor::parse_session_bytes(&bytes).unwrap().len(),
+        SPANS
+    );
+}
+
+#[test]
+fn profile_catch_keeps_the_panic_hook_from_ending_the_session() {
+    let _serial = serial();
+    Instrumentor::install_panic_hook();
+    let bytes = record_session("catch", || {
+        let result = Instrumentor::profile_catch("catch", || panic!("caught"));
+        assert!(result.is_err());
+        InstrumentationTimer::new("after");
+    });
+    // Puts the default hook back.
+    drop(std::panic::take_hook());
+    assert_eq!(Instrumentor::late_event_count(), 0);
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    let names: Vec<&str> = spans.iter().map(|span| span.name.as_str()).collect();
+    assert_eq!(names, ["catch", "after"]);
+    assert_eq!(
+        spans[0].args,
+        [("panicked".to_string(), ArgValue::Bool(true))]
+    );
+}
+
+/// Spans that began before their session are cut off at its start instead
+/// of being moved there whole.
+#[test]
+fn spans_begun_before_the_session_keep_their_end() {
+    let _serial = serial();
+    let early = Instant::now();
+    let manual = Instrumentor::begin_span("manual");
+    std::thread::sleep(Duration::from_millis(30));
+    let bytes = record_session("clamp", || {
+        std::thread::sleep(Duration::from_millis(5));
+        InstrumentationTimer::from_start("from_start", early);
+        Instrumentor::end_span(manual);
+    });
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    assert_eq!(spans.len(), 2);
+    for span in spans {
+        assert_eq!(span.start, 0, "{}", span.name);
+        assert!(
+            (5_000..30_000).contains(&span.end),
+            "{} ends at {}",
+            span.name,
+            span.end
+        );
+    }
+}
+
+#[test]
+fn wide_integers_saturate_into_int_args() {
+    assert_eq!(ArgValue::from(3usize), ArgValue::Int(3));
+    assert_eq!(ArgValue::from(-3isize), ArgValue::Int(-3));
+    assert_eq!(ArgValue::from(u64::MAX), ArgValue::Int(i64::MAX));
+    assert_eq!(ArgValue::from(i128::MIN), ArgValue::Int(i64::MIN));
+}
+
+/// A caught strict-nesting panic mustn't leave the timer's name on the
+/// thread's stacks.
+#[test]
+fn strict_nesting_panic_pops_the_timer() {
+    let _serial = serial();
+    Instrumentor::set_collapse_recursion(true);
+    Instrumentor::set_strict_nesting(true);
+    let mut outer = InstrumentationTimer::new("outer");
+    let inner = InstrumentationTimer::new("inner");
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| outer.stop()));
+    Instrumentor::set_strict_nesting(false);
+    drop(inner);
+    Instrumentor::set_collapse_recursion(false);
+    assert!(result.is_err());
+    assert!(OPEN_NAMES.with_borrow(Vec::is_empty));
+}
+
+/// Spans stopping on other threads while the session ends are either
+/// written before the footer or dropped as late, never written after it.
+#[test]
+fn session_ending_under_load_stays_parseable() {
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Arc,
+    };
+
+    let _serial = serial();
+    const THREADS: usize = 8;
+    for round in 0..5 {
+        let path = temp_path(&format!("ending-{round}"));
+        let stop = Arc::new(AtomicBool::new(false));
+        let running = Arc::new(AtomicUsize::new(0));
+        Instrumentor::begin_session("ending", &path);
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let stop = Arc::clone(&stop);
+                let running = Arc::clone(&running);
+                std::thread::spawn(move || {
+                    InstrumentationTimer::new("first");
+                    running.fetch_add(1, Ordering::Relaxed);
+                    while !stop.load(Ordering::Relaxed) {
+                        InstrumentationTimer::new("span");
+                    }
+                })
+            })
+            .collect();
+        while running.load(Ordering::Relaxed) < THREADS {
+            std::thread::yield_now();
+        }
+        Instrumentor::end_session();
+        stop.store(true, Ordering::Relaxed);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Fails on anything after the footer.
+        let trace: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert!(trace["traceEvents"].is_array());
+        assert!(Instrumentor::parse_session_bytes(&bytes).unwrap().len() >= THREADS);
+    }
+}
+
+/// Both timers of two `tracing!` calls in one scope live to its end.
+#[test]
+fn tracing_twice_in_one_scope_times_the_whole_scope_twice() {
+    let _serial = serial();
+    let bytes = record_session("twice", || {
+        tracing!("first");
+        tracing!("second");
+        {
+            let _body = InstrumentationTimer::new("body");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    });
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    let span = |name: &str| spans.iter().find(|span| span.name == name).unwrap();
+    let (first, second, body) = (span("first"), span("second"), span("body"));
+    assert!(first.start <= second.start && second.start <= body.start);
+    assert!(first.end > body.end && second.end > body.end);
+    assert!(first.end >= second.end);
+}
+
+/// Checks `trace` has no comma before its first event and one between each
+/// of its events, `spans` of which are spans.
+fn assert_commas(trace: &[u8], spans: usize) {
+    let text = std::str::from_utf8(trace).unwrap();
+    let events = &text[text.find("\"traceEvents\":[").unwrap() + 15..];
+    assert!(events.trim_start().starts_with('{'), "{text}");
+    let trace: serde_json::Value = serde_json::from_slice(trace).unwrap();
+    let written = trace["traceEvents"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|event| event["ph"] == "X")
+        .count();
+    assert_eq!(written, spans);
+}
+
+#[test]
+fn session_files_place_commas_between_events() {
+    let _serial = serial();
+    for session in ["commas-1", "commas-2"] {
+        let bytes = record_session(session, || {
+            for _ in 0..3 {
+                InstrumentationTimer::new("span");
+            }
+        });
+        assert_commas(&bytes, 3);
+    }
+}
+
+/// A writer whose bytes can still be read once it's been handed over.
+#[derive(Clone, Default)]
+struct SharedBuffer(std::sync::Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn json_sinks_place_commas_between_events() {
+    let _serial = serial();
+    let buffer = SharedBuffer::default();
+    Instrumentor::add_observer(Box::new(JsonSink::new(buffer.clone())));
+    for session in ["sink-commas-1", "sink-commas-2"] {
+        record_session(session, || {
+            for _ in 0..3 {
+                InstrumentationTimer::new("span");
+            }
+        });
+        // Each session is a trace of its own, starting with a fresh stream.
+        let bytes = std::mem::take(&mut *buffer.0.lock().unwrap());
+        assert_commas(&bytes, 3);
+    }
+    Instrumentor::clear_observers();
+}
+
+/// A [`SharedBuffer`] whose writes fail, like a full disk, while `full` is
+/// set.
+#[derive(Clone, Default)]
+struct FillingBuffer {
+    buffer: SharedBuffer,
+    full: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl std::io::Write for FillingBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.full.load(std::sync::atomic::Ordering::Relaxed) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                "disk full",
+            ));
+        }
+        self.buffer.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn failing_writes_stop_the_session_writing() {
+    let _serial = serial();
+    let writer = FillingBuffer::default();
+    let guard = Instrumentor::begin_session_with_writer("failing", writer.clone()).unwrap();
+    InstrumentationTimer::new("before");
+    assert!(Instrumentor::last_error().is_none());
+
+    writer
+        .full
+        .store(true, std::sync::atomic::Ordering::Relaxed);
+    InstrumentationTimer::new("failing");
+    let err = Instrumentor::last_error().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+
+    // Once there's room again, nothing more is written to the stream given up
+    // on.
+    writer
+        .full
+        .store(false, std::sync::atomic::Ordering::Relaxed);
+    InstrumentationTimer::new("after");
+    drop(guard);
+    let written = String::from_utf8(writer.buffer.0.lock().unwrap().clone()).unwrap();
+    assert!(written.contains("\"before\""));
+    assert!(!written.contains("\"failing\""));
+    assert!(!written.contains("\"after\""));
+    assert_eq!(
+        Instrumentor::last_error().unwrap().kind(),
+        std::io::ErrorKind::StorageFull
+    );
+}
+
+/// Every phase in [`schema::PHASES`] is written with exactly the fields
+/// [`schema::chrome_fields`] lists, in its order.
+#[test]
+fn events_have_the_fields_of_their_phase() {
+    let _serial = serial();
+    Instrumentor::set_emit_session_markers(true);
+    let bytes = record_session("schema", || {
+        InstrumentationTimer::with_args("span", vec![("n".to_string(), ArgValue::Int(1))]);
+        Instrumentor::counter_add("series", 1.0);
+        Instrumentor::write_samples(vec![(current_track_id(), vec!["span".to_string()])]);
+        Instrumentor::async_begin("op", AsyncId::Id(1));
+        Instrumentor::async_end("op", AsyncId::Local(1));
+    });
+    Instrumentor::set_emit_session_markers(false);
+
+    let mut events = reader::TraceReader::new(bytes.as_slice()).unwrap();
+    let mut phases = Vec::new();
+    while let Some(event) = events.next_event().unwrap() {
+        let keys: Vec<String> = reader::fields(&event)
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let phase = reader::string(reader::field(&event, "ph").unwrap()).unwrap();
+        let phase = phase.chars().next().unwrap();
+        // Async events carry only the id field of their kind of id.
+        let unused_id = match phase {
+            'b' => "id2",
+            'e' => "id",
+            _ => "",
+        };
+        let expected: Vec<&str> = schema::chrome_fields(phase)
+            .iter()
+            .copied()
+            .filter(|field| *field != unused_id)
+            .collect();
+        assert_eq!(keys, expected, "{event}");
+        phases.push(phase);
+    }
+    for phase in schema::PHASES {
+        assert!(phases.contains(phase), "no {phase:?} event in {phases:?}");
+    }
+}
+
+/// Events other than spans are indexed too, so a window after the last span
+/// starts reading at them.
+#[test]
+fn index_covers_events_other_than_spans() {
+    let _serial = serial();
+    let path = temp_path("index");
+    Instrumentor::enable_index(Duration::from_micros(100));
+    Instrumentor::begin_session("index", &path);
+    InstrumentationTimer::new("span");
+    std::thread::sleep(Duration::from_millis(2));
+    Instrumentor::counter_add("series", 1.0);
+    Instrumentor::end_session();
+    Instrumentor::disable_index();
+    let trace = std::fs::read_to_string(&path).unwrap();
+    let index = std::fs::read_to_string(index::index_path(&path)).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(index::index_path(&path)).unwrap();
+
+    let last_bucket = index
+        .lines()
+        .rfind(|line| line.starts_with(|c: char| c.is_ascii_digit()))
+        .unwrap();
+    let (_, offset) = last_bucket.split_once(' ').unwrap();
+    let at = trace[offset.parse::<usize>().unwrap()..].trim_start_matches([',', '\n', ' ']);
+    assert!(at.starts_with("{\"args\":{\"series\""), "{at}");
+}
+
+/// Reading a window stops once nothing later can overlap it, so a broken
+/// event after that isn't even parsed.
+#[test]
+fn reading_a_window_stops_after_it() {
+    let _serial = serial();
+    let span = |name: &str, start, end| ProfileResult {
+        name: name.to_string(),
+        start,
+        end,
+        thread_id: 1,
+        depth: 0,
+        args: Vec::new(),
+        tags: Vec::new(),
+    };
+    let path = temp_path("index-window");
+    Instrumentor::enable_index(Duration::from_micros(100));
+    Instrumentor::begin_session("index-window", &path);
+    Instrumentor::write_profile(&span("before", 0, 10));
+    Instrumentor::write_profile(&span("inside", 1_000, 1_010));
+    Instrumentor::write_profile(&span("after", 2_000, 2_010));
+    Instrumentor::write_profile(&span("broken", 3_000, 3_010));
+    Instrumentor::end_session();
+    Instrumentor::disable_index();
+
+    let mut trace = std::fs::read_to_string(&path).unwrap();
+    let broken = trace.find(r#""name":"broken""#).unwrap();
+    let broken = trace[..broken].rfind('{').unwrap();
+    trace.replace_range(broken..broken + 1, "x");
+    std::fs::write(&path, trace).unwrap();
+
+    let window = Instrumentor::read_trace_window(&path, 1_000, 1_005).unwrap();
+    let names: Vec<&str> = window
+        .iter()
+        .filter_map(|event| reader::field(event, "name"))
+        .collect();
+    assert_eq!(names, [r#""inside""#]);
+    // Reading on would have hit the broken event.
+    assert!(Instrumentor::read_trace_window(&path, 1_000, 3_000).is_err());
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(index::index_path(&path)).unwrap();
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn traces_serialize_as_their_fields() {
+    let mut trace = Trace::new("serde");
+    trace.push(ProfileResult {
+        name: "span".to_string(),
+        start: 1,
+        end: 3,
+        thread_id: 7,
+        depth: 0,
+        args: vec![("n".to_string(), ArgValue::Int(2))],
+        tags: vec!["io"],
+    });
+    assert_eq!(
+        serde_json::to_value(&trace).unwrap(),
+        serde_json::json!({
+            "name": "serde",
+            "unix_micros": null,
+            "events": [{
+                "name": "span",
+                "start": 1,
+                "end": 3,
+                "thread_id": 7,
+                "depth": 0,
+                "args": [["n", 2]],
+                "tags": ["io"],
+            }],
+        })
+    );
+}
+
+#[test]
+fn environment_counts_cpus_and_threads() {
+    let _serial = serial();
+    Instrumentor::set_emit_environment(true);
+    let bytes = record_session("environment", || {});
+    Instrumentor::set_emit_environment(false);
+    let trace: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let environment = &trace["otherData"]["environment"];
+    assert!(environment["cpus"].as_u64().unwrap() >= 1);
+    if cfg!(target_os = "linux") {
+        assert!(environment["threads"].as_u64().unwrap() >= 1);
+    }
+}
+
+#[test]
+fn perfetto_json_notes_session_relative_timestamps() {
+    let _serial = serial();
+    Instrumentor::set_format(TraceFormat::PerfettoJson);
+    let bytes = record_session("perfetto-json", || {
+        InstrumentationTimer::new("span");
+    });
+    Instrumentor::set_format(TraceFormat::ChromeTracing);
+    let trace: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let metadata = trace["metadata"].as_object().unwrap();
+    assert!(!metadata.contains_key("clock-domain"));
+    assert_eq!(metadata["tsOrigin"], "sessionStart");
+    assert_eq!(Instrumentor::parse_session_bytes(&bytes).unwrap().len(), 1);
+}
+
+/// A guard outliving its session leaves the sessions begun after it alone.
+#[test]
+fn stale_session_guard_leaves_later_sessions_running() {
+    let _serial = serial();
+    let buffer = SharedBuffer::default();
+    let guard = Instrumentor::begin_session_with_writer("a", buffer.clone()).unwrap();
+    Instrumentor::end_session();
+    let path = temp_path("stale-guard");
+    Instrumentor::begin_session("b", &path);
+    drop(guard);
+    assert_eq!(Instrumentor::session_path().as_deref(), Some(path.as_str()));
+    Instrumentor::end_session();
+    std::fs::remove_file(&path).unwrap();
+
+    let guard = Instrumentor::begin_session_with_writer("c", buffer.clone()).unwrap();
+    Instrumentor::end_session();
+    let other = Instrumentor::begin_session_with_writer("d", buffer).unwrap();
+    Instrumentor::end_session();
+    // The writer left behind is the one of session "d".
+    assert!(guard.finish().is_none());
+    assert!(other.finish().is_some());
+}
+
+/// A child beginning in the same microsecond as its parent is still nested
+/// under it, although it ends and is recorded first.
+#[cfg(feature = "perfetto")]
+#[test]
+fn perfetto_nests_children_beginning_with_their_parent() {
+    let _serial = serial();
+    let span = |name: &str, start, end, depth| ProfileResult {
+        name: name.to_string(),
+        start,
+        end,
+        thread_id: 1,
+        depth,
+        args: Vec::new(),
+        tags: Vec::new(),
+    };
+    Instrumentor::set_format(TraceFormat::PerfettoProto);
+    let bytes = record_session("perfetto-nesting", || {
+        Instrumentor::write_profile(&span("grandchild", 5, 6, 2));
+        Instrumentor::write_profile(&span("child", 5, 8, 1));
+        Instrumentor::write_profile(&span("sibling", 8, 10, 1));
+        Instrumentor::write_profile(&span("parent", 5, 10, 0));
+        Instrumentor::write_profile(&span("next", 10, 12, 0));
+    });
+    Instrumentor::set_format(TraceFormat::ChromeTracing);
+
+    let mut slices = perfetto::decode_slices(&bytes);
+    slices.sort_by_key(|(_, depth, start, _)| (*start, *depth));
+    let expected = [
+        ("parent", 0, 5_000, 10_000),
+        ("child", 1, 5_000, 8_000),
+        ("grandchild", 2, 5_000, 6_000),
+        ("sibling", 1, 8_000, 10_000),
+        ("next", 0, 10_000, 12_000),
+    ];
+    let slices: Vec<_> = slices
+        .iter()
+        .map(|(name, depth, start, end)| (name.as_str(), *depth, *start, *end))
+        .collect();
+    assert_eq!(slices, expected);
+}
+
+/// The idle event written before a top-level span doesn't write the span's
+/// children ahead of it.
+#[cfg(feature = "perfetto")]
+#[test]
+fn perfetto_nests_children_after_an_idle_gap() {
+    let _serial = serial();
+    let span = |name: &str, start, end, depth| ProfileResult {
+        name: name.to_string(),
+        start,
+        end,
+        thread_id: 1,
+        depth,
+        args: Vec::new(),
+        tags: Vec::new(),
+    };
+    Instrumentor::set_format(TraceFormat::PerfettoProto);
+    Instrumentor::set_emit_idle(true);
+    let gap = IDLE_THRESHOLD_MICROS + 1;
+    let bytes = record_session("perfetto-idle", || {
+        Instrumentor::write_profile(&span("first", 0, 10, 0));
+        Instrumentor::write_profile(&span("child", 10 + gap, 20 + gap, 1));
+        Instrumentor::write_profile(&span("parent", 10 + gap, 30 + gap, 0));
+    });
+    Instrumentor::set_emit_idle(false);
+    Instrumentor::set_format(TraceFormat::ChromeTracing);
+
+    let mut slices = perfetto::decode_slices(&bytes);
+    slices.sort_by_key(|(_, depth, start, _)| (*start, *depth));
+    let slices: Vec<_> = slices
+        .iter()
+        .map(|(name, depth, start, end)| (name.as_str(), *depth, *start, *end))
+        .collect();
+    let micros = |micros: i64| micros as u64 * 1000;
+    assert_eq!(
+        slices,
+        [
+            ("first", 0, 0, micros(10)),
+            ("idle", 0, micros(10), micros(10 + gap)),
+            ("parent", 0, micros(10 + gap), micros(30 + gap)),
+            ("child", 1, micros(10 + gap), micros(20 + gap)),
+        ]
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn session_markers_carry_the_process_uptime() {
+    let _serial = serial();
+    let before = process_start().unwrap().elapsed();
+    Instrumentor::set_emit_session_markers(true);
+    let bytes = record_session("uptime", || std::thread::sleep(Duration::from_millis(5)));
+    Instrumentor::set_emit_session_markers(false);
+    let trace: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let uptime = |name: &str| {
+        trace["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|event| event["name"] == name)
+            .unwrap()["args"]["uptimeMicros"]
+            .as_i64()
+            .unwrap()
+    };
+    let (begin, end) = (uptime("SessionBegin"), uptime("SessionEnd"));
+    assert!(begin >= duration_to_micros(before), "{begin}");
+    assert!(end - begin >= 5_000, "{begin}..{end}");
+}
+
+#[test]
+fn session_parse_errors_tell_framing_from_truncation_and_bad_events() {
+    let header = r#"{"otherData": {},"traceEvents":["#;
+    let span = r#"{"cat":"function","dur":1,"name":"a","ph":"X","pid":0,"tid":1,"ts":0}"#;
+    let parse = |trace: String| Instrumentor::parse_session_bytes(trace.as_bytes());
+    assert_eq!(parse(format!("{header}{span},{span}]}}")).unwrap().len(), 2);
+    assert!(matches!(
+        parse(format!("{header}{span},,{span}]}}")),
+        Err(ParseError::Framing(_))
+    ));
+    assert!(matches!(
+        parse(format!("{header}{span},]}}")),
+        Err(ParseError::Framing(_))
+    ));
+    assert!(matches!(
+        parse(format!("{header}{span},{{\"ph\"")),
+        Err(ParseError::Truncated)
+    ));
+    assert!(matches!(
+        parse(format!(r#"{header}{{"ph":"X","name":"a"}}]}}"#)),
+        Err(ParseError::Json(_))
+    ));
+}
+
+/// Batched spans of every thread go into the session they were recorded in,
+/// whether their thread still runs when it ends or not.
+#[test]
+fn single_threaded_batches_of_every_thread_stay_in_their_session() {
+    let _serial = serial();
+    let names = |bytes: &[u8]| {
+        let mut names: Vec<String> = Instrumentor::parse_session_bytes(bytes)
+            .unwrap()
+            .into_iter()
+            .map(|span| span.name)
+            .collect();
+        names.sort();
+        names
+    };
+    Instrumentor::set_single_threaded(true);
+    let (resume, resumed) = std::sync::mpsc::channel::<()>();
+    let mut running = None;
+    let first = record_session("batches-1", || {
+        InstrumentationTimer::new("main");
+        std::thread::spawn(|| {
+            InstrumentationTimer::new("exited");
+        })
+        .join()
+        .unwrap();
+        let (recorded, record) = std::sync::mpsc::channel();
+        running = Some(std::thread::spawn(move || {
+            InstrumentationTimer::new("running");
+            recorded.send(()).unwrap();
+            resumed.recv().unwrap();
+            InstrumentationTimer::new("later");
+        }));
+        record.recv().unwrap();
+    });
+    let second = record_session("batches-2", || {
+        resume.send(()).unwrap();
+        running.take().unwrap().join().unwrap();
+    });
+    Instrumentor::set_single_threaded(false);
+    assert_eq!(names(&first), ["exited", "main", "running"]);
+    assert_eq!(names(&second), ["later"]);
+}
+
+fn pending(name: &str) -> queue::Pending {
+    queue::Pending {
+        name: name.to_string(),
+        start: Instant::now(),
+        duration: 0,
+        thread_id: 1,
+        depth: 0,
+        args: Vec::new(),
+        tags: Cow::Borrowed(&[]),
+    }
+}
+
+/// Fills a queue of 2 spans without a writer taking them, pushing `a`, `b`
+/// and `c`, and returns the names left in it.
+fn overflow(policy: OverflowPolicy) -> Vec<String> {
+    queue::open(2, policy);
+    for name in ["a", "b", "c"] {
+        assert!(queue::push(pending(name)).is_ok());
+    }
+    let (_, spans) = queue::close();
+    spans.into_iter().map(|pending| pending.name).collect()
+}
+
+#[test]
+fn full_queues_drop_the_span_their_policy_says() {
+    let _serial = serial();
+    assert_eq!(overflow(OverflowPolicy::DropNewest), ["a", "b"]);
+    assert_eq!(Instrumentor::dropped_count(), 1);
+    assert_eq!(overflow(OverflowPolicy::DropOldest), ["b", "c"]);
+    assert_eq!(Instrumentor::dropped_count(), 1);
+}
+
+/// A blocked timer waits for room, and gets its span back to write itself
+/// if the queue closes first.
+#[test]
+fn full_blocking_queues_wait_for_room() {
+    let _serial = serial();
+    queue::open(1, OverflowPolicy::Block);
+    assert!(queue::push(pending("a")).is_ok());
+    let blocked = std::thread::spawn(|| queue::push(pending("b")).map_err(|pending| pending.name));
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(!blocked.is_finished());
+    let (_, spans) = queue::close();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(blocked.join().unwrap(), Err("b".to_string()));
+    assert_eq!(Instrumentor::dropped_count(), 0);
+
+    // Spans queued by a session's writer are all written, none dropped.
+    Instrumentor::set_async_writing(Some(1));
+    let bytes = record_session("blocking-queue", || {
+        for _ in 0..50 {
+            InstrumentationTimer::new("span");
+        }
+    });
+    Instrumentor::set_async_writing(None);
+    assert_eq!(Instrumentor::parse_session_bytes(&bytes).unwrap().len(), 50);
+    assert_eq!(Instrumentor::dropped_count(), 0);
+}
+
+/// The shard files of this process left in the temp directory.
+fn shard_files() -> Vec<std::path::PathBuf> {
+    let prefix = format!("simple-tracing-{}-", std::process::id());
+    std::fs::read_dir(std::env::temp_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            name.strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix(".json"))
+                .and_then(|rest| rest.split_once('-'))
+                .is_some_and(|(generation, shard)| {
+                    [generation, shard]
+                        .iter()
+                        .all(|n| n.bytes().all(|b| b.is_ascii_digit()))
+                })
+        })
+        .collect()
+}
+
+#[test]
+fn sharded_sessions_merge_every_thread_and_remove_their_shards() {
+    let _serial = serial();
+    const THREADS: usize = 4;
+    const SPANS: usize = 10;
+    Instrumentor::set_sharded(true);
+    let mut shards = Vec::new();
+    let bytes = record_session("sharded", || {
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    for _ in 0..SPANS {
+                        InstrumentationTimer::new("span");
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        shards = shard_files();
+    });
+    Instrumentor::set_sharded(false);
+    assert_eq!(shards.len(), THREADS);
+    assert!(shard_files().is_empty());
+
+    let trace: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let events = trace["traceEvents"].as_array().unwrap();
+    let names = events.iter().filter(|event| event["ph"] == "M").count();
+    assert_eq!(names, THREADS);
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    assert_eq!(spans.len(), THREADS * SPANS);
+    let threads: HashSet<u32> = spans.iter().map(|span| span.thread_id).collect();
+    assert_eq!(threads.len(), THREADS);
+}
+
+#[test]
+fn sharded_sessions_filter_and_cut_names() {
+    let _serial = serial();
+    Instrumentor::set_sharded(true);
+    Instrumentor::set_name_filter(Box::new(|name| {
+        Cow::Owned(name.replace("secret", "[redacted]"))
+    }));
+    Instrumentor::set_max_name_len(15);
+    let bytes = record_session("sharded-filter", || {
+        std::thread::spawn(|| {
+            InstrumentationTimer::new("user secret");
+            InstrumentationTimer::new("a secret and then some");
+        })
+        .join()
+        .unwrap();
+    });
+    Instrumentor::set_sharded(false);
+    Instrumentor::clear_name_filter();
+    Instrumentor::set_max_name_len(0);
+
+    assert!(!String::from_utf8_lossy(&bytes).contains("secret"));
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    let names: Vec<&str> = spans.iter().map(|span| span.name.as_str()).collect();
+    assert_eq!(names, ["user [redacted]", "a [redacted] a…"]);
+}
+
+/// Converts the binary trace `bytes` to JSON.
+fn binary_to_chrome(bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    let (in_path, out_path) = (temp_path("binary.bin"), temp_path("binary.json"));
+    std::fs::write(&in_path, bytes).unwrap();
+    let converted = Instrumentor::binary_to_chrome(&in_path, &out_path);
+    std::fs::remove_file(&in_path).unwrap();
+    let json = converted.map(|()| std::fs::read(&out_path).unwrap());
+    let _ = std::fs::remove_file(&out_path);
+    json
+}
+
+#[test]
+fn binary_traces_convert_back_to_their_spans() {
+    let _serial = serial();
+    Instrumentor::set_format(TraceFormat::Binary);
+    let bytes = record_session("binary", || {
+        for (name, start, end) in [("a", 1, 4), (AWKWARD_NAME, 5, 9), ("a", 10, 10)] {
+            Instrumentor::write_profile(&ProfileResult {
+                name: name.to_string(),
+                start,
+                end,
+                thread_id: 7,
+                depth: 0,
+                args: Vec::new(),
+                tags: Vec::new(),
+            });
+        }
+    });
+    Instrumentor::set_format(TraceFormat::ChromeTracing);
+
+    let json = binary_to_chrome(&bytes).unwrap();
+    let spans: Vec<_> = Instrumentor::parse_session_bytes(&json)
+        .unwrap()
+        .into_iter()
+        .map(|span| (span.name, span.start, span.end, span.thread_id))
+        .collect();
+    assert_eq!(
+        spans,
+        [
+            ("a".to_string(), 1, 4, 7),
+            (AWKWARD_NAME.to_string(), 5, 9, 7),
+            ("a".to_string(), 10, 10, 7),
+        ]
+    );
+
+    // Cut off in the last record, the trace converts up to the one before.
+    let json = binary_to_chrome(&bytes[..bytes.len() - 3]).unwrap();
+    assert_eq!(Instrumentor::parse_session_bytes(&json).unwrap().len(), 2);
+}
+
+#[test]
+fn binary_traces_with_a_bad_magic_or_tag_are_rejected() {
+    let _serial = serial();
+    let mut header = b"STRC".to_vec();
+    header.extend_from_slice(&SCHEMA_VERSION.to_le_bytes());
+    let mut bad_magic = header.clone();
+    bad_magic[0] = b'X';
+    let mut bad_tag = header;
+    bad_tag.push(9);
+    for bytes in [bad_magic, bad_tag] {
+        let err = binary_to_chrome(&bytes).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
+
+/// A string record claiming more bytes than the file holds ends the
+/// conversion like any other cut off record, without allocating them.
+#[test]
+fn binary_strings_longer_than_the_file_are_truncation() {
+    let _serial = serial();
+    let mut bytes = b"STRC".to_vec();
+    bytes.extend_from_slice(&SCHEMA_VERSION.to_le_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+    bytes.extend_from_slice(b"short");
+    let json = binary_to_chrome(&bytes).unwrap();
+    assert!(Instrumentor::parse_session_bytes(&json).unwrap().is_empty());
+}
+
+/// Durations of spans from the far ends of the clock saturate instead of
+/// overflowing, whatever the format.
+#[test]
+fn extreme_spans_are_written_without_overflowing() {
+    let _serial = serial();
+    let span = ProfileResult {
+        name: "extreme".to_string(),
+        start: i64::MIN,
+        end: i64::MAX,
+        thread_id: 1,
+        depth: 0,
+        args: Vec::new(),
+        tags: Vec::new(),
+    };
+    Instrumentor::set_emit_idle(true);
+    for format in [TraceFormat::ChromeTracing, TraceFormat::Binary] {
+        Instrumentor::set_format(format);
+        record_session("extreme", || {
+            Instrumentor::write_profile(&span);
+            Instrumentor::write_profile(&span);
+        });
+    }
+    Instrumentor::set_format(TraceFormat::ChromeTracing);
+    Instrumentor::set_emit_idle(false);
+    assert_eq!(Instrumentor::summary()["extreme"].max, i64::MAX);
+}
+
+#[test]
+fn span_ends_past_i64_saturate() {
+    let event = format!(
+        r#"{{"dur":{},"name":"a","ph":"X","pid":0,"tid":1,"ts":{}}}"#,
+        i64::MAX,
+        i64::MAX
+    );
+    let span = reader::span(&event).unwrap().unwrap();
+    assert_eq!(span.end, i64::MAX);
+}
+
+/// Writes a trace with `header` fields and `events` to a temp file named
+/// `name`, returning its path.
+fn trace_file(name: &str, header: &str, events: &[String]) -> String {
+    let path = temp_path(name);
+    let trace = format!(
+        r#"{{"otherData": {{{header}}},"traceEvents":[{}]}}"#,
+        events.join(",")
+    );
+    std::fs::write(&path, trace).unwrap();
+    path
+}
+
+/// A JSON span event.
+fn span_event(name: &str, ts: i64, dur: i64, tid: &str) -> String {
+    format!(
+        r#"{{"cat":"function","dur":{dur},"name":"{name}","ph":"X","pid":0,"tid":{tid},"ts":{ts}}}"#
+    )
+}
+
+/// Reads and removes the JSON trace at `path`.
+fn take_trace(path: &str) -> serde_json::Value {
+    let trace = serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+    std::fs::remove_file(path).unwrap();
+    trace
+}
+
+#[test]
+fn downsampling_keeps_long_spans_and_events_without_a_duration() {
+    let _serial = serial();
+    let thread_name = r#"{"args":{"name":"main"},"cat":"__metadata","name":"thread_name","ph":"M","pid":0,"tid":1}"#;
+    let in_path = trace_file(
+        "downsample-in",
+        r#""schema":1"#,
+        &[
+            thread_name.to_string(),
+            span_event("short", 0, 9, "1"),
+            span_event("exact", 10, 10, "1"),
+            span_event("long", 20, 500, "1"),
+        ],
+    );
+    let out_path = temp_path("downsample-out");
+    Instrumentor::downsample_trace(&in_path, &out_path, 10).unwrap();
+    std::fs::remove_file(&in_path).unwrap();
+
+    let trace = take_trace(&out_path);
+    assert_eq!(trace["otherData"]["schema"], 1);
+    let names: Vec<&str> = trace["traceEvents"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|event| event["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["thread_name", "exact", "long"]);
+}
+
+/// The `ts` of every event of `trace`.
+fn timestamps(trace: &serde_json::Value) -> Vec<i64> {
+    trace["traceEvents"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|event| event["ts"].as_i64())
+        .collect()
+}
+
+#[test]
+fn rebasing_starts_a_trace_at_zero_and_shifts_its_clock_sync() {
+    let _serial = serial();
+    let rebase = |header: &str, events: &[String]| {
+        let in_path = trace_file("rebase-in", header, events);
+        let out_path = temp_path("rebase-out");
+        Instrumentor::rebase_trace(&in_path, &out_path).unwrap();
+        std::fs::remove_file(&in_path).unwrap();
+        take_trace(&out_path)
+    };
+
+    let trace = rebase(
+        r#""clockSync":{"ts":0,"unixMicros":1000}"#,
+        &[span_event("b", 150, 10, "1"), span_event("a", 100, 80, "1")],
+    );
+    assert_eq!(timestamps(&trace), [50, 0]);
+    assert_eq!(trace["otherData"]["clockSync"]["unixMicros"], 1100);
+
+    let trace = rebase(
+        "",
+        &[
+            span_event("min", i64::MIN, 0, "1"),
+            span_event("max", i64::MAX, 0, "1"),
+        ],
+    );
+    assert_eq!(timestamps(&trace), [0, i64::MAX]);
+}
+
+#[test]
+fn splitting_by_thread_keeps_processes_apart() {
+    let _serial = serial();
+    let process_name =
+        r#"{"args":{"name":"p"},"cat":"__metadata","name":"process_name","ph":"M","pid":1}"#;
+    let mut other_process = span_event("other", 2, 1, "5");
+    other_process = other_process.replace("\"pid\":0", "\"pid\":1");
+    let in_path = trace_file(
+        "split-in",
+        "",
+        &[
+            span_event("a", 0, 1, "5"),
+            process_name.to_string(),
+            other_process,
+            span_event("b", 3, 1, "\"6\""),
+        ],
+    );
+    let out_dir = temp_path("split-out");
+    let paths = Instrumentor::split_trace_by_thread(&in_path, &out_dir).unwrap();
+    let file_names: Vec<String> = paths
+        .iter()
+        .map(|path| {
+            let path = std::path::Path::new(path);
+            path.file_name().unwrap().to_string_lossy().into_owned()
+        })
+        .collect();
+    assert_eq!(file_names, ["0-5.json", "0-6.json", "1-5.json"]);
+    let names: Vec<Vec<String>> = paths
+        .iter()
+        .map(|path| {
+            take_trace(path)["traceEvents"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|event| event["name"].as_str().unwrap().to_string())
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        names,
+        [
+            vec!["a", "process_name"],
+            vec!["process_name", "b"],
+            vec!["process_name", "other"],
+        ]
+    );
+
+    let escaping = trace_file("split-escaping", "", &[span_event("a", 0, 1, "\"../x\"")]);
+    let err = Instrumentor::split_trace_by_thread(&escaping, &out_dir).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    std::fs::remove_file(&in_path).unwrap();
+    std::fs::remove_file(&escaping).unwrap();
+    std::fs::remove_dir(&out_dir).unwrap();
+}
+
+#[test]
+fn merging_shifts_traces_onto_one_clock_and_gives_each_its_own_process() {
+    let _serial = serial();
+    let sample = r#"{"name":"sample","ph":"P","pid":0,"sf":1,"tid":1,"ts":5}"#;
+    let early = trace_file(
+        "merge-early",
+        r#""clockSync":{"ts":0,"unixMicros":1000}"#,
+        &[span_event("early", 10, 5, "1"), sample.to_string()],
+    );
+    let late = trace_file(
+        "merge-late",
+        r#""clockSync":{"ts":0,"unixMicros":1500}"#,
+        &[span_event("late", 10, 5, "1")],
+    );
+    let unsynced = trace_file("merge-unsynced", "", &[span_event("unsynced", 10, 5, "1")]);
+    let out_path = temp_path("merge-out");
+    Instrumentor::merge_traces(&[&early, &late, &unsynced], &out_path).unwrap();
+    let trace = take_trace(&out_path);
+
+    assert_eq!(trace["otherData"]["clockSync"]["unixMicros"], 1000);
+    let events = trace["traceEvents"].as_array().unwrap();
+    let spans: Vec<(&str, i64, i64)> = events
+        .iter()
+        .filter(|event| event["ph"] == "X")
+        .map(|event| {
+            let name = event["name"].as_str().unwrap();
+            (
+                name,
+                event["ts"].as_i64().unwrap(),
+                event["pid"].as_i64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        spans,
+        [("early", 10, 0), ("late", 510, 1), ("unsynced", 10, 2)]
+    );
+    let processes: Vec<(&str, i64)> = events
+        .iter()
+        .filter(|event| event["name"] == "process_name")
+        .map(|event| {
+            (
+                event["args"]["name"].as_str().unwrap(),
+                event["pid"].as_i64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        processes,
+        [
+            (early.as_str(), 0),
+            (late.as_str(), 1),
+            (unsynced.as_str(), 2)
+        ]
+    );
+    assert!(events.iter().all(|event| event["ph"] != "P"));
+    for path in [early, late, unsynced] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn repairing_drops_a_half_written_event_and_copies_an_intact_trace() {
+    let _serial = serial();
+    let intact = trace_file(
+        "repair-intact",
+        r#""schema":1"#,
+        &[span_event("a", 0, 5, "1"), span_event("b", 10, 5, "1")],
+    );
+    let bytes = std::fs::read(&intact).unwrap();
+    let out_path = temp_path("repair-out");
+    assert!(!Instrumentor::repair_trace(&intact, &out_path).unwrap());
+    assert_eq!(std::fs::read(&out_path).unwrap(), bytes);
+
+    // Cut the trace off halfway through its last event.
+    let truncated = temp_path("repair-truncated");
+    let cut = bytes.len() - span_event("b", 10, 5, "1").len() / 2 - 2;
+    std::fs::write(&truncated, &bytes[..cut]).unwrap();
+    assert!(Instrumentor::repair_trace(&truncated, &out_path).unwrap());
+    let trace = take_trace(&out_path);
+    assert_eq!(trace["otherData"]["schema"], 1);
+    let names: Vec<&str> = trace["traceEvents"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|event| event["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["a"]);
+    std::fs::remove_file(&intact).unwrap();
+    std::fs::remove_file(&truncated).unwrap();
+}
+
+#[test]
+fn collapsing_recursion_skips_only_directly_nested_namesakes() {
+    let _serial = serial();
+    Instrumentor::set_collapse_recursion(true);
+    let bytes = record_session("collapse", || {
+        {
+            let _outer = InstrumentationTimer::new("a");
+            let _middle = InstrumentationTimer::new("a");
+            let _inner = InstrumentationTimer::new("a");
+        }
+        {
+            let _outer = InstrumentationTimer::new("a");
+            let _middle = InstrumentationTimer::new("b");
+            let _inner = InstrumentationTimer::new("a");
+        }
+    });
+    Instrumentor::set_collapse_recursion(false);
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    let mut names: Vec<&str> = spans.iter().map(|span| span.name.as_str()).collect();
+    names.sort();
+    assert_eq!(names, ["a", "a", "a", "b"]);
+    assert_eq!(Instrumentor::collapsed_count(), 2);
+}
+
+#[test]
+fn histogram_buckets_split_powers_of_two_in_sixteen() {
+    use histogram::{bucket_index, bucket_middle};
+
+    // Exact below 16, then 16 buckets per power of two.
+    for value in 0..32 {
+        assert_eq!(bucket_index(value), value as usize);
+        assert_eq!(bucket_middle(value as usize), value);
+    }
+    assert_eq!(bucket_index(32), 32);
+    assert_eq!(bucket_index(33), 32);
+    assert_eq!(bucket_index(34), 33);
+    assert_eq!(bucket_index(63), 47);
+    assert_eq!(bucket_index(64), 48);
+    assert_eq!(bucket_index(67), 48);
+    assert_eq!(bucket_index(68), 49);
+    assert_eq!(bucket_middle(32), 33);
+    assert_eq!(bucket_middle(48), 66);
+
+    // Every value lands in a bucket covering it, whose middle is close.
+    let mut last_index = 0;
+    let values: std::collections::BTreeSet<u64> = (0..20)
+        .flat_map(|exponent| {
+            let power = 1u64 << exponent;
+            [power - 1, power, power + 1]
+        })
+        .collect();
+    for value in values {
+        let index = bucket_index(value);
+        assert!(index >= last_index);
+        last_index = index;
+        let middle = bucket_middle(index);
+        assert!(
+            middle.abs_diff(value) as f64 <= value as f64 * 0.06,
+            "{value}"
+        );
+    }
+}
+
+#[test]
+fn histogram_quantiles_stay_within_a_bucket() {
+    let mut histogram = histogram::Histogram::default();
+    assert_eq!(histogram.percentiles(), histogram::Percentiles::default());
+    for duration in 1..=100 {
+        histogram.record(duration);
+    }
+    let percentiles = histogram.percentiles();
+    assert_eq!(percentiles.count, 100);
+    // 50 is in [50, 52), 90 in [88, 92) and 99 in [96, 100).
+    assert_eq!(percentiles.p50, 51);
+    assert_eq!(percentiles.p90, 90);
+    assert_eq!(percentiles.p99, 98);
+
+    // The middle of a bucket is clamped to the values seen.
+    let mut histogram = histogram::Histogram::default();
+    histogram.record(32);
+    histogram.record(-5);
+    let percentiles = histogram.percentiles();
+    assert_eq!((percentiles.p50, percentiles.p99), (0, 32));
+}
+
+#[test]
+fn critical_path_follows_the_longest_child_down() {
+    let span = |name: &str, start, end, thread_id, depth| ProfileResult {
+        name: name.to_string(),
+        start,
+        end,
+        thread_id,
+        depth,
+        args: Vec::new(),
+        tags: Vec::new(),
+    };
+    // Out of order, and a child starting in the same microsecond as its
+    // parent.
+    let events = [
+        span("short", 42, 48, 1, 2),
+        span("load", 0, 30, 1, 1),
+        span("worker", 0, 90, 2, 0),
+        span("long", 50, 80, 1, 2),
+        span("main", 0, 100, 1, 0),
+        span("compute", 40, 95, 1, 1),
+        span("worker child", 10, 20, 2, 1),
+    ];
+    let names: Vec<String> = analysis::critical_path(&events)
+        .into_iter()
+        .map(|span| span.name)
+        .collect();
+    assert_eq!(names, ["main", "compute", "long"]);
+    assert!(analysis::critical_path(&[]).is_empty());
+}
+
+#[test]
+fn diffing_traces_compares_totals_by_name() {
+    let _serial = serial();
+    let baseline = trace_file(
+        "diff-baseline",
+        "",
+        &[
+            span_event("same", 0, 10, "1"),
+            span_event("slower", 10, 20, "1"),
+            span_event("slower", 30, 20, "1"),
+            span_event("gone", 50, 5, "1"),
+        ],
+    );
+    let candidate = trace_file(
+        "diff-candidate",
+        "",
+        &[
+            span_event("same", 0, 10, "1"),
+            span_event("slower", 10, 70, "1"),
+            span_event(r#"new, \"quoted\""#, 80, 3, "1"),
+        ],
+    );
+    let diffs = Instrumentor::diff_traces(&baseline, &candidate).unwrap();
+    std::fs::remove_file(&baseline).unwrap();
+    std::fs::remove_file(&candidate).unwrap();
+    let summary: Vec<(&str, i64, i64, Option<f64>)> = diffs
+        .iter()
+        .map(|diff| {
+            (
+                diff.name.as_str(),
+                diff.total_delta,
+                diff.mean_delta,
+                diff.total_change,
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("slower", 30, 50, Some(75.0)),
+            ("gone", -5, -5, Some(-100.0)),
+            ("new, \"quoted\"", 3, 3, None),
+            ("same", 0, 0, Some(0.0)),
+        ]
+    );
+    assert_eq!(diffs[0].baseline.count, 2);
+    assert_eq!(diffs[0].candidate.count, 1);
+    assert_eq!(diffs[1].candidate, NameStats::default());
+
+    let csv_path = temp_path("diff.csv");
+    Instrumentor::write_diff_csv(&diffs, &csv_path).unwrap();
+    let csv = std::fs::read_to_string(&csv_path).unwrap();
+    std::fs::remove_file(&csv_path).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[0].starts_with("name,baseline_count,candidate_count,"));
+    assert_eq!(lines[1], "slower,2,1,40,70,30,75.0,20,70,50");
+    assert_eq!(lines[3], "\"new, \"\"quoted\"\"\",0,1,0,3,3,,0,3,3");
+}
+
+#[test]
+fn exported_html_embeds_the_trace_and_a_viewer() {
+    let _serial = serial();
+    let trace_path = trace_file(
+        "export<&>.json",
+        r#""schema":1"#,
+        &[
+            span_event("</script><b>", 0, 5, "1"),
+            span_event("<!--<script> & more", 5, 5, "1"),
+        ],
+    );
+    let html_path = temp_path("export.html");
+    Instrumentor::export_html(&trace_path, &html_path).unwrap();
+    let html = std::fs::read_to_string(&html_path).unwrap();
+    std::fs::remove_file(&html_path).unwrap();
+
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert!(html.contains("export&lt;&amp;&gt;.json</title>"));
+    assert!(html.contains(r#"<div id="viewer"></div>"#));
+    assert!(!html.contains("<script src"));
+    let (_, embedded) = html
+        .split_once(r#"<script type="application/json" id="trace">"#)
+        .unwrap();
+    let (embedded, _) = embedded.split_once("</script>").unwrap();
+    // The names are escaped, to the same JSON.
+    assert!(!embedded.contains(['<', '>', '&']));
+    let embedded: serde_json::Value = serde_json::from_str(embedded).unwrap();
+    assert_eq!(embedded, take_trace(&trace_path));
+}
+
+#[test]
+fn session_state_is_kept_until_the_next_session_unless_reset_on_end() {
+    let _serial = serial();
+    let record = || {
+        record_session("reset", || {
+            InstrumentationTimer::new("span");
+        });
+    };
+    record();
+    assert!(Instrumentor::event_count() > 0);
+    assert_eq!(Instrumentor::summary()["span"].count, 1);
+
+    Instrumentor::set_reset_on_end(true);
+    record();
+    Instrumentor::set_reset_on_end(false);
+    assert_eq!(Instrumentor::event_count(), 0);
+    assert!(Instrumentor::summary().is_empty());
+    let stats = Instrumentor::last_session_stats().unwrap();
+    assert_eq!(stats.name, "reset");
+    assert!(stats.event_count > 0);
+    assert!(stats.bytes > 0);
+}
+
+#[test]
+fn encoded_len_counts_the_bytes_a_span_adds() {
+    let _serial = serial();
+    let span = |name: &str, start, end| ProfileResult {
+        name: name.to_string(),
+        start,
+        end,
+        thread_id: 1,
+        depth: 0,
+        args: vec![("key".to_string(), "value".into())],
+        tags: vec!["tag"],
+    };
+    let spans = [
+        span("first", 0, 10),
+        span("a name cut short", 20, 30),
+        // After a gap long enough for an idle event.
+        span("first", 1_000_000, 1_000_010),
+    ];
+    Instrumentor::set_max_name_len(6);
+    Instrumentor::set_record_walltime(true);
+    Instrumentor::set_stamp_sequence(true);
+    Instrumentor::set_emit_idle(true);
+    let formats = [
+        (TraceFormat::ChromeTracing, false),
+        (TraceFormat::ChromeTracing, true),
+        (TraceFormat::Binary, false),
+    ];
+    #[cfg(feature = "perfetto")]
+    let formats = [&formats[..], &[(TraceFormat::PerfettoProto, false)]].concat();
+    for (format, pretty) in formats {
+        Instrumentor::set_format(format);
+        Instrumentor::set_pretty(pretty);
+        let buffer = SharedBuffer::default();
+        let guard = Instrumentor::begin_session_with_writer("encoded-len", buffer.clone()).unwrap();
+        for span in &spans {
+            let expected = span.encoded_len(format);
+            let before = buffer.0.lock().unwrap().len();
+            Instrumentor::write_profile(span);
+            let written = buffer.0.lock().unwrap().len() - before;
+            assert_eq!(written, expected, "{format:?} {}", span.name);
+        }
+        drop(guard);
+        assert_eq!(spans[0].encoded_len(format), 0);
+    }
+    Instrumentor::set_format(TraceFormat::ChromeTracing);
+    Instrumentor::set_pretty(false);
+    Instrumentor::set_max_name_len(0);
+    Instrumentor::set_record_walltime(false);
+    Instrumentor::set_stamp_sequence(false);
+    Instrumentor::set_emit_idle(false);
+}
+
+#[test]
+fn implausibly_long_spans_are_reported_through_last_error() {
+    let _serial = serial();
+    let span = |name: &str, end| ProfileResult {
+        name: name.to_string(),
+        start: 0,
+        end,
+        thread_id: 1,
+        depth: 0,
+        args: Vec::new(),
+        tags: Vec::new(),
+    };
+    record_session("implausible", || {
+        Instrumentor::write_profile(&span("plausible", 1_000_000));
+    });
+    assert!(Instrumentor::last_error().is_none());
+
+    record_session("implausible", || {
+        Instrumentor::write_profile(&span("two years", 2 * 365 * 24 * 60 * 60 * 1_000_000));
+    });
+    let err = Instrumentor::last_error().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("`two years`"));
+}
+
+#[test]
+fn record_windows_and_predicates_drop_the_spans_they_exclude() {
+    let _serial = serial();
+    let span = |name: &str, start| ProfileResult {
+        name: name.to_string(),
+        start,
+        end: start + 1,
+        thread_id: 1,
+        depth: 0,
+        args: Vec::new(),
+        tags: Vec::new(),
+    };
+    let names = |bytes: &[u8]| -> Vec<String> {
+        let spans = Instrumentor::parse_session_bytes(bytes).unwrap();
+        spans.into_iter().map(|span| span.name).collect()
+    };
+
+    Instrumentor::set_record_window(Duration::from_micros(10), Duration::from_micros(20));
+    let bytes = record_session("record-window", || {
+        for (name, start) in [
+            ("early", 9),
+            ("first", 10),
+            ("middle", 15),
+            ("last", 20),
+            ("late", 21),
+        ] {
+            Instrumentor::write_profile(&span(name, start));
+        }
+    });
+    Instrumentor::clear_record_window();
+    assert_eq!(names(&bytes), ["first", "middle", "last"]);
+    assert!(!Instrumentor::summary().contains_key("early"));
+
+    Instrumentor::set_record_predicate(Box::new(|name| !name.starts_with("skip")));
+    let bytes = record_session("record-predicate", || {
+        for name in ["kept", "skipped", "also kept", "skip"] {
+            Instrumentor::write_profile(&span(name, 0));
+        }
+    });
+    Instrumentor::clear_record_predicate();
+    assert_eq!(names(&bytes), ["kept", "also kept"]);
+    assert!(!Instrumentor::summary().contains_key("skipped"));
+}
+
+#[test]
+fn long_names_are_cut_on_character_boundaries() {
+    let _serial = serial();
+    Instrumentor::set_max_name_len(4);
+    let bytes = record_session("max-name-len", || {
+        // Two and three byte characters, cutting inside either by bytes would
+        // panic.
+        for name in ["ünïcödé", "日本語の名前", "ünï", "ünïc", "短い"] {
+            InstrumentationTimer::new(name);
+        }
+    });
+    Instrumentor::set_max_name_len(0);
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    let names: Vec<_> = spans.iter().map(|span| span.name.as_str()).collect();
+    assert_eq!(names, ["ünï…", "日本語…", "ünï", "ünïc", "短い"]);
+}
+
+#[test]
+fn tracing_if_records_only_when_its_condition_holds() {
+    let _serial = serial();
+    let bytes = record_session("tracing-if", || {
+        for record in [false, true] {
+            tracing_if!(record, "plain");
+            {
+                tracing_if!(record, "with args", record = record);
+            }
+        }
+    });
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    let names: Vec<_> = spans.iter().map(|span| span.name.as_str()).collect();
+    assert_eq!(names, ["with args", "plain"]);
+    assert_eq!(
+        spans[0].args,
+        [("record".to_string(), ArgValue::from(true))]
+    );
+}
+
+#[cfg(all(feature = "ftrace", target_os = "linux"))]
+#[test]
+fn ftrace_markers_keep_names_on_one_line() {
+    let marker = ftrace::begin_marker(AWKWARD_NAME);
+    assert_eq!(
+        marker,
+        format!(
+            "B|{}|C:\\Users\\me \"quoted\" second line",
+            std::process::id()
+        )
+    );
+}
+
+#[test]
+fn with_session_ends_its_session_however_it_returns() {
+    let _serial = serial();
+    let path = temp_path("with-session");
+    let value = Instrumentor::with_session("with-session", &path, || {
+        InstrumentationTimer::new("span");
+        assert_eq!(Instrumentor::session_path().as_deref(), Some(path.as_str()));
+        // Sessions don't nest.
+        assert!(Instrumentor::with_session("nested", &temp_path("nested"), || ()).is_err());
+        42
+    });
+    assert_eq!(value.unwrap(), 42);
+    assert_eq!(Instrumentor::session_path(), None);
+    let spans = Instrumentor::parse_session_bytes(&std::fs::read(&path).unwrap()).unwrap();
+    assert_eq!(spans.len(), 1);
+
+    let panicked = std::panic::catch_unwind(|| {
+        Instrumentor::with_session("with-session", &path, || {
+            InstrumentationTimer::new("span");
+            panic!("inside the session");
+        })
+    });
+    assert!(panicked.is_err());
+    assert_eq!(Instrumentor::session_path(), None);
+    let spans = Instrumentor::parse_session_bytes(&std::fs::read(&path).unwrap()).unwrap();
+    assert_eq!(spans.len(), 1);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn summaries_by_thread_keep_namesakes_on_other_threads_apart() {
+    let _serial = serial();
+    let span = |thread_id, duration| ProfileResult {
+        name: "work".to_string(),
+        start: 0,
+        end: duration,
+        thread_id,
+        depth: 0,
+        args: Vec::new(),
+        tags: Vec::new(),
+    };
+    record_session("summary-by-thread", || {
+        for span in [span(1, 10), span(1, 30), span(2, 5)] {
+            Instrumentor::write_profile(&span);
+        }
+    });
+    let by_thread = Instrumentor::summary_by_thread();
+    assert_eq!(by_thread.len(), 2);
+    let busy = by_thread[&(1, "work".to_string())];
+    assert_eq!(
+        (busy.count, busy.total, busy.min, busy.max),
+        (2, 40, 10, 30)
+    );
+    let idle = by_thread[&(2, "work".to_string())];
+    assert_eq!((idle.count, idle.total, idle.min, idle.max), (1, 5, 5, 5));
+
+    let summary = Instrumentor::summary();
+    let work = summary["work"];
+    assert_eq!((work.count, work.total, work.min, work.max), (3, 45, 5, 30));
+}
+
+#[test]
+fn pretty_sessions_put_each_event_on_its_own_line() {
+    let _serial = serial();
+    let record = || {
+        record_session("pretty", || {
+            for _ in 0..3 {
+                InstrumentationTimer::new("span");
+            }
+        })
+    };
+    let compact = String::from_utf8(record()).unwrap();
+    Instrumentor::set_pretty(true);
+    let pretty = String::from_utf8(record()).unwrap();
+    Instrumentor::set_pretty(false);
+
+    assert!(!compact.contains('\n'));
+    let lines: Vec<_> = pretty.lines().collect();
+    assert!(lines[0].ends_with('['));
+    assert!(lines.last().unwrap().starts_with(']'));
+    let events = &lines[1..lines.len() - 1];
+    assert!(events.len() >= 3);
+    for event in events {
+        let event = event.strip_prefix("  ").unwrap();
+        serde_json::from_str::<serde_json::Value>(event.trim_end_matches(',')).unwrap();
+    }
+    let spans = Instrumentor::parse_session_bytes(pretty.as_bytes()).unwrap();
+    assert_eq!(spans.len(), 3);
+}
+
+#[test]
+fn sampling_records_the_open_spans_as_stack_frames() {
+    let _serial = serial();
+    Instrumentor::enable_sampling(Duration::from_millis(1));
+    let bytes = record_session("sampling", || {
+        let _outer = InstrumentationTimer::new("outer");
+        let _inner = InstrumentationTimer::new("inner");
+        std::thread::sleep(Duration::from_millis(50));
+    });
+    Instrumentor::disable_sampling();
+    let trace: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let samples: Vec<_> = trace["traceEvents"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|event| event["ph"] == "P")
+        .collect();
+    assert!(!samples.is_empty());
+
+    // The innermost open span is sampled, its parent frame is the one
+    // around it.
+    let frames = &trace["stackFrames"];
+    let frame = |id: &serde_json::Value| &frames[id.to_string()];
+    let inner = samples
+        .iter()
+        .map(|sample| frame(&sample["sf"]))
+        .find(|frame| frame["name"] == "inner")
+        .unwrap();
+    assert_eq!(frame(&inner["parent"])["name"], "outer");
+    assert!(frame(&inner["parent"]).get("parent").is_none());
+}
+
+/// The `thread_name` metadata events of a JSON trace, by `tid`.
+fn thread_names(trace: &serde_json::Value) -> HashMap<u32, String> {
+    trace["traceEvents"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|event| event["name"] == "thread_name")
+        .map(|event| {
+            let tid = u32::try_from(event["tid"].as_u64().unwrap()).unwrap();
+            (tid, event["args"]["name"].as_str().unwrap().to_string())
+        })
+        .collect()
+}
+
+#[test]
+fn tracks_record_their_spans_on_rows_of_their_own() {
+    let _serial = serial();
+    let bytes = record_session("tracks", || {
+        for track in [7, 8, 7] {
+            Instrumentor::set_current_track(track);
+            InstrumentationTimer::new("task");
+        }
+        Instrumentor::clear_current_track();
+        InstrumentationTimer::new("thread");
+    });
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    let tids: Vec<_> = spans.iter().map(|span| span.thread_id).collect();
+    assert_eq!(tids, [7, 8, 7, current_thread_id()]);
+
+    let trace: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let names = thread_names(&trace);
+    assert_eq!(names[&7], "7");
+    assert_eq!(names[&8], "8");
+}
+
+#[test]
+fn correlation_ids_are_added_to_the_args_of_this_threads_spans() {
+    let _serial = serial();
+    let bytes = record_session("correlation", || {
+        InstrumentationTimer::new("before");
+        Instrumentor::set_correlation_id(Some(CorrelationId {
+            trace_id: AWKWARD_NAME.to_string(),
+            span_id: Some("00f067aa0ba902b7".to_string()),
+        }));
+        InstrumentationTimer::new("both");
+        // Other threads have ids of their own.
+        std::thread::spawn(|| {
+            InstrumentationTimer::new("other thread");
+        })
+        .join()
+        .unwrap();
+        Instrumentor::set_correlation_id(Some(CorrelationId {
+            trace_id: "4bf92f3577b34da6".to_string(),
+            span_id: None,
+        }));
+        InstrumentationTimer::new("trace only");
+        Instrumentor::set_correlation_id(None);
+        InstrumentationTimer::new("after");
+    });
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    let args = |name: &str| {
+        let span = spans.iter().find(|span| span.name == name).unwrap();
+        span.args.clone()
+    };
+    let arg = |key: &str, value: &str| (key.to_string(), ArgValue::from(value));
+    assert_eq!(args("before"), []);
+    assert_eq!(
+        args("both"),
+        [
+            arg("trace_id", AWKWARD_NAME),
+            arg("span_id", "00f067aa0ba902b7")
+        ]
+    );
+    assert_eq!(args("other thread"), []);
+    assert_eq!(args("trace only"), [arg("trace_id", "4bf92f3577b34da6")]);
+    assert_eq!(args("after"), []);
+}
+
+#[test]
+fn threads_are_named_once_after_the_name_they_were_spawned_with() {
+    let _serial = serial();
+    let mut tids = (0, 0);
+    let bytes = record_session("thread-names", || {
+        let spawn = |builder: std::thread::Builder| {
+            let thread = builder.spawn(|| {
+                InstrumentationTimer::new("first");
+                InstrumentationTimer::new("second");
+                current_thread_id()
+            });
+            thread.unwrap().join().unwrap()
+        };
+        tids = (
+            spawn(std::thread::Builder::new().name("worker-1".to_string())),
+            spawn(std::thread::Builder::new()),
+        );
+    });
+    let (named, unnamed) = tids;
+    let trace: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let names = thread_names(&trace);
+    assert_eq!(names[&named], "worker-1");
+    assert_eq!(names[&unnamed], unnamed.to_string());
+    let events = trace["traceEvents"].as_array().unwrap();
+    let named_events = events
+        .iter()
+        .filter(|event| event["name"] == "thread_name" && event["tid"] == named)
+        .count();
+    assert_eq!(named_events, 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn raw_fd_sessions_leave_the_fd_open() {
+    use std::{io::Write, os::unix::io::AsRawFd};
+
+    let _serial = serial();
+    let path = temp_path("raw-fd");
+    let mut file = std::fs::File::create(&path).unwrap();
+    // SAFETY: `file` is open until after the session ends.
+    unsafe { Instrumentor::begin_session_from_raw_fd("raw-fd", file.as_raw_fd()) }.unwrap();
+    InstrumentationTimer::new("span");
+    Instrumentor::end_session();
+
+    // The caller's fd still works once the session is over.
+    file.write_all(b"\n").unwrap();
+    drop(file);
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(bytes.last(), Some(&b'\n'));
+    let spans = Instrumentor::parse_session_bytes(bytes.trim_ascii_end()).unwrap();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].name, "span");
+}
+
+#[test]
+fn last_event_is_the_span_recorded_last() {
+    let _serial = serial();
+    let path = temp_path("last-event");
+    let last_event =
+        || Instrumentor::last_event().map(|event| (event.name, event.start, event.end));
+    Instrumentor::begin_session("last-event", &path);
+    assert_eq!(last_event(), None);
+    Instrumentor::set_max_name_len(5);
+    {
+        let _outer = InstrumentationTimer::new("outer span");
+        InstrumentationTimer::new("inner");
+        assert_eq!(Instrumentor::last_event().unwrap().name, "inner");
+    }
+    Instrumentor::set_max_name_len(0);
+    let last = last_event();
+    assert_eq!(last.as_ref().unwrap().0, "oute…");
+
+    // Spans that aren't recorded don't replace it.
+    Instrumentor::set_record_predicate(Box::new(|name| name != "dropped"));
+    InstrumentationTimer::new("dropped");
+    Instrumentor::clear_record_predicate();
+    assert_eq!(last_event(), last);
+
+    Instrumentor::end_session();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(last_event(), last);
+}
+
+#[test]
+fn panic_hook_ends_the_session_and_runs_the_previous_hook() {
+    static PREVIOUS_RAN: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+    let _serial = serial();
+    std::panic::set_hook(Box::new(|_| {
+        PREVIOUS_RAN.store(true, std::sync::atomic::Ordering::Relaxed)
+    }));
+    Instrumentor::install_panic_hook();
+    let path = temp_path("panic-hook");
+    Instrumentor::begin_session("panic-hook", &path);
+    InstrumentationTimer::new("before");
+    let panicked = std::thread::spawn(|| panic!("uncaught")).join();
+    // Puts the default hook back.
+    drop(std::panic::take_hook());
+    assert!(panicked.is_err());
+    assert!(PREVIOUS_RAN.load(std::sync::atomic::Ordering::Relaxed));
+
+    // The session was ended by the hook, with its footer written.
+    assert_eq!(Instrumentor::session_path(), None);
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].name, "before");
+}
+
+#[test]
+fn child_spans_record_their_parent_across_threads() {
+    let _serial = serial();
+    let bytes = record_session("begin-child", || {
+        let root = Instrumentor::begin_span("request");
+        let child = std::thread::spawn(move || Instrumentor::begin_child("callback", root))
+            .join()
+            .unwrap();
+        // Ended on another thread than the one that began it, and twice.
+        Instrumentor::end_span(child);
+        Instrumentor::end_span(child);
+        Instrumentor::end_span(root);
+    });
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    let names: Vec<_> = spans.iter().map(|span| span.name.as_str()).collect();
+    assert_eq!(names, ["callback", "request"]);
+    let (child, root) = (&spans[0], &spans[1]);
+    assert_eq!(root.args.len(), 1);
+    let (key, root_id) = &root.args[0];
+    assert_eq!(key, "id");
+    assert_eq!(child.args[1], ("parent".to_string(), root_id.clone()));
+    assert_ne!(child.args[0].1, *root_id);
+    assert_ne!(child.thread_id, root.thread_id);
+    assert_eq!(root.thread_id, current_thread_id());
+}
+
+#[test]
+fn only_slow_spans_carry_a_backtrace() {
+    let _serial = serial();
+    Instrumentor::set_capture_backtrace_over(Some(5_000));
+    let bytes = record_session("backtraces", || {
+        InstrumentationTimer::new("fast");
+        let _slow = InstrumentationTimer::new("slow");
+        std::thread::sleep(Duration::from_millis(10));
+    });
+    Instrumentor::set_capture_backtrace_over(None);
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    assert_eq!(spans[0].name, "fast");
+    assert_eq!(spans[0].args, []);
+    assert_eq!(spans[1].name, "slow");
+    let [(key, ArgValue::Str(backtrace))] = &spans[1].args[..] else {
+        panic!("no backtrace in {:?}", spans[1].args);
+    };
+    assert_eq!(key, "backtrace");
+    // Starts at the code that timed the span, not inside the instrumentor.
+    let first_frame = backtrace.lines().next().unwrap();
+    assert!(
+        first_frame.contains("only_slow_spans_carry_a_backtrace"),
+        "{backtrace}"
+    );
+    assert!(!backtrace.contains("trimmed_backtrace"));
+}
+
+#[test]
+fn buffered_output_is_flushed_every_n_events_or_interval() {
+    let _serial = serial();
+    let len = |buffer: &SharedBuffer| buffer.0.lock().unwrap().len();
+
+    Instrumentor::set_flush_every(3);
+    let buffer = SharedBuffer::default();
+    let guard = Instrumentor::begin_session_with_writer("flush-every", buffer.clone()).unwrap();
+    // The thread's name comes with its first span, so this counts from the
+    // first flush on.
+    let header = len(&buffer);
+    while len(&buffer) == header {
+        InstrumentationTimer::new("span");
+    }
+    for _ in 0..2 {
+        let flushed = len(&buffer);
+        InstrumentationTimer::new("span");
+        InstrumentationTimer::new("span");
+        assert_eq!(len(&buffer), flushed);
+        InstrumentationTimer::new("span");
+        assert!(len(&buffer) > flushed);
+    }
+    drop(guard);
+
+    Instrumentor::set_flush_every(0);
+    Instrumentor::set_flush_interval(Some(Duration::from_millis(5)));
+    let buffer = SharedBuffer::default();
+    let guard = Instrumentor::begin_session_with_writer("flush-interval", buffer.clone()).unwrap();
+    let before = len(&buffer);
+    InstrumentationTimer::new("span");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while len(&buffer) == before {
+        assert!(Instant::now() < deadline, "never flushed");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    InstrumentationTimer::new("last");
+    // Ending the session stops the flusher and flushes the rest.
+    drop(guard);
+    Instrumentor::set_flush_interval(None);
+    Instrumentor::set_flush_every(1);
+    let spans = Instrumentor::parse_session_bytes(&buffer.0.lock().unwrap()).unwrap();
+    let names: Vec<_> = spans.iter().map(|span| span.name.as_str()).collect();
+    assert_eq!(names, ["span", "last"]);
+}
+
+#[test]
+fn fail_if_exists_keeps_existing_traces() {
+    let _serial = serial();
+    let path = temp_path("fail-if-exists");
+    std::fs::write(&path, "unfinished").unwrap();
+
+    Instrumentor::set_fail_if_exists(true);
+    let err = Instrumentor::try_begin_session("fail-if-exists", &path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    assert_eq!(Instrumentor::session_path(), None);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "unfinished");
+
+    // Paths that don't exist yet are created as usual.
+    std::fs::remove_file(&path).unwrap();
+    Instrumentor::try_begin_session("fail-if-exists", &path).unwrap();
+    Instrumentor::end_session();
+    Instrumentor::set_fail_if_exists(false);
+
+    // By default the file is truncated.
+    Instrumentor::try_begin_session("fail-if-exists", &path).unwrap();
+    Instrumentor::end_session();
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(Instrumentor::parse_session_bytes(&bytes)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn raw_events_are_written_with_exactly_their_fields() {
+    let _serial = serial();
+    let bytes = record_session("raw-events", || {
+        InstrumentationTimer::new("span");
+        Instrumentor::write_raw_event(&[
+            ("name", AWKWARD_NAME.into()),
+            ("ph", "R".into()),
+            ("pid", 0.into()),
+            ("tid", 1.into()),
+            ("ts", 5.into()),
+            (
+                "args",
+                JsonValue::Object(vec![
+                    (
+                        "list".to_string(),
+                        JsonValue::Array(vec![1.into(), JsonValue::Null]),
+                    ),
+                    ("ok".to_string(), true.into()),
+                ]),
+            ),
+        ]);
+        InstrumentationTimer::new("span");
+    });
+    // Commas and escaping are taken care of, the spans around it still parse.
+    let trace: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let raw = trace["traceEvents"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|event| event["ph"] == "R")
+        .unwrap();
+    assert_eq!(
+        *raw,
+        serde_json::json!({
+            "name": AWKWARD_NAME,
+            "ph": "R",
+            "pid": 0,
+            "tid": 1,
+            "ts": 5,
+            "args": {"list": [1, null], "ok": true},
+        })
+    );
+    assert_eq!(Instrumentor::parse_session_bytes(&bytes).unwrap().len(), 2);
+
+    // Raw events outside a session are late like any other.
+    Instrumentor::write_raw_event(&[("ph", "R".into())]);
+    assert_eq!(Instrumentor::late_event_count(), 1);
+}
+
+#[test]
+fn totals_sessions_write_only_the_accumulated_totals() {
+    let _serial = serial();
+    Instrumentor::set_format(TraceFormat::Totals);
+    let bytes = record_session("totals", || {
+        Instrumentor::accumulate("parse", Duration::from_micros(30));
+        Instrumentor::accumulate("parse", Duration::from_micros(20));
+        Instrumentor::accumulate("a, b", Duration::from_micros(7));
+        Instrumentor::write_profile(&ProfileResult {
+            name: "span".to_string(),
+            start: 0,
+            end: 10,
+            thread_id: 1,
+            depth: 0,
+            args: Vec::new(),
+            tags: Vec::new(),
+        });
+    });
+    Instrumentor::set_format(TraceFormat::ChromeTracing);
+    assert_eq!(
+        String::from_utf8(bytes).unwrap(),
+        "name,count,total_us\nparse,2,50\nspan,1,10\n\"a, b\",1,7\n"
+    );
+    assert_eq!(Instrumentor::summary()["parse"].total, 50);
+}
+
+#[test]
+fn async_ids_are_written_in_the_form_of_their_scope() {
+    let _serial = serial();
+    let bytes = record_session("id2", || {
+        for id in [AsyncId::Id(5), AsyncId::Local(5), AsyncId::Global(5)] {
+            Instrumentor::async_begin("operation", id);
+            Instrumentor::async_end("operation", id);
+        }
+    });
+    let trace: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let ids: Vec<_> = trace["traceEvents"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|event| event["cat"] == "async")
+        .map(|event| (event["id"].clone(), event["id2"].clone()))
+        .collect();
+    let legacy = (serde_json::json!(5), serde_json::Value::Null);
+    let local = (serde_json::Value::Null, serde_json::json!({"local": 5}));
+    let global = (serde_json::Value::Null, serde_json::json!({"global": 5}));
+    assert_eq!(
+        ids,
+        [
+            legacy.clone(),
+            legacy,
+            local.clone(),
+            local,
+            global.clone(),
+            global
+        ]
+    );
+}
+
+#[test]
+fn tracing_loop_names_each_iteration_after_its_index() {
+    let _serial = serial();
+    let bytes = record_session("tracing-loop", || {
+        for i in 0..3 {
+            tracing_loop!("iter", i);
+        }
+        for (i, item) in ["a", "b"].into_iter().enumerate() {
+            tracing_loop!("item", i, value = item);
+        }
+    });
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    let names: Vec<_> = spans.iter().map(|span| span.name.as_str()).collect();
+    assert_eq!(
+        names,
+        ["iter[0]", "iter[1]", "iter[2]", "item[0]", "item[1]"]
+    );
+    assert_eq!(spans[4].args, [("value".to_string(), ArgValue::from("b"))]);
+    // One after the other, not nested.
+    assert!(spans.windows(2).all(|pair| pair[0].end <= pair[1].start));
+}
+
+/// Logs the calls it gets, prefixed with its own label.
+struct LoggingObserver {
+    label: &'static str,
+    log: std::sync::Arc<Mutex<Vec<String>>>,
+}
+
+impl SessionObserver for LoggingObserver {
+    fn on_begin(&mut self, name: &str) {
+        self.log
+            .lock()
+            .unwrap()
+            .push(format!("{} begin {name}", self.label));
+    }
+
+    fn on_event(&mut self, result: &ProfileResult) {
+        let entry = format!("{} event {}", self.label, result.name);
+        self.log.lock().unwrap().push(entry);
+    }
+
+    fn on_end(&mut self) {
+        self.log.lock().unwrap().push(format!("{} end", self.label));
+    }
+}
+
+#[test]
+fn observers_follow_every_session_from_begin_to_end() {
+    let _serial = serial();
+    let log = std::sync::Arc::default();
+    for label in ["a", "b"] {
+        Instrumentor::add_observer(Box::new(LoggingObserver {
+            label,
+            log: std::sync::Arc::clone(&log),
+        }));
+    }
+    Instrumentor::set_record_predicate(Box::new(|name| name != "dropped"));
+    Instrumentor::set_max_name_len(4);
+    for session in ["first", "second"] {
+        record_session(session, || {
+            InstrumentationTimer::new("dropped");
+            InstrumentationTimer::new("recorded");
+        });
+    }
+    Instrumentor::set_max_name_len(0);
+    Instrumentor::clear_record_predicate();
+    Instrumentor::clear_observers();
+    // No longer observed.
+    record_session("third", || {
+        InstrumentationTimer::new("recorded");
+    });
+
+    let log = log.lock().unwrap();
+    let expected: Vec<String> = ["first", "second"]
+        .into_iter()
+        .flat_map(|session| {
+            [
+                format!("begin {session}"),
+                "event rec…".to_string(),
+                "end".to_string(),
+            ]
+        })
+        .flat_map(|call| [format!("a {call}"), format!("b {call}")])
+        .collect();
+    assert_eq!(*log, expected);
+}
+
+#[test]
+fn tagged_spans_are_summarized_by_each_of_their_tags() {
+    let _serial = serial();
+    let span = |name: &str, duration, tags| ProfileResult {
+        name: name.to_string(),
+        start: 0,
+        end: duration,
+        thread_id: 1,
+        depth: 0,
+        args: Vec::new(),
+        tags,
+    };
+    let bytes = record_session("tags", || {
+        Instrumentor::write_profile(&span("read", 10, vec!["io", "hot"]));
+        Instrumentor::write_profile(&span("read", 30, vec!["io"]));
+        Instrumentor::write_profile(&span("parse", 5, vec!["hot"]));
+        Instrumentor::write_profile(&span("untagged", 1, Vec::new()));
+        tracing_tagged!("macro", ["io", "v2"]);
+    });
+
+    let io = Instrumentor::summary_by_tag("io");
+    let mut names: Vec<_> = io.keys().map(String::as_str).collect();
+    names.sort();
+    assert_eq!(names, ["macro", "read"]);
+    assert_eq!((io["read"].count, io["read"].total), (2, 40));
+    let hot = Instrumentor::summary_by_tag("hot");
+    assert_eq!(hot.len(), 2);
+    assert_eq!((hot["read"].count, hot["read"].total), (1, 10));
+    assert_eq!((hot["parse"].count, hot["parse"].total), (1, 5));
+    assert!(Instrumentor::summary_by_tag("cold").is_empty());
+
+    // Written as an array in the args.
+    let trace: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let tags: Vec<_> = trace["traceEvents"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|event| event["ph"] == "X")
+        .map(|event| event["args"]["tags"].clone())
+        .collect();
+    assert_eq!(
+        tags,
+        [
+            serde_json::json!(["io", "hot"]),
+            serde_json::json!(["io"]),
+            serde_json::json!(["hot"]),
+            serde_json::Value::Null,
+            serde_json::json!(["io", "v2"]),
+        ]
+    );
+}
+
+#[test]
+fn walltime_is_the_session_start_plus_the_relative_ts() {
+    let _serial = serial();
+    let now = || {
+        let since_epoch = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+        since_epoch.unwrap().as_micros() as i64
+    };
+    let span = |start| ProfileResult {
+        name: "span".to_string(),
+        start,
+        end: start + 1,
+        thread_id: 1,
+        depth: 0,
+        args: Vec::new(),
+        tags: Vec::new(),
+    };
+    Instrumentor::set_record_walltime(true);
+    let before = now();
+    let bytes = record_session("walltime", || {
+        Instrumentor::write_profile(&span(0));
+        Instrumentor::write_profile(&span(1_000_000));
+    });
+    let after = now();
+    Instrumentor::set_record_walltime(false);
+
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    let walls: Vec<i64> = spans
+        .iter()
+        .map(|span| match span.args[..] {
+            [(ref key, ArgValue::Int(wall))] if key == "wall" => wall,
+            _ => panic!("no wall arg in {:?}", span.args),
+        })
+        .collect();
+    assert!((before..=after).contains(&walls[0]), "{walls:?}");
+    assert_eq!(walls[1] - walls[0], 1_000_000);
+}
+
+#[test]
+fn spans_are_written_with_what_was_set_on_them_by_their_end() {
+    let _serial = serial();
+    let bytes = record_session("span-handle", || {
+        let mut span = Instrumentor::span("query");
+        span.set_arg("rows", 1);
+        span.add_tag("db");
+        span.set_arg("cached", false);
+        span.set_arg("rows", 42);
+        span.add_tag("db");
+        span.add_tag("slow");
+        span.end();
+
+        let mut dropped = Instrumentor::span("dropped");
+        dropped.set_arg("key", "value");
+    });
+    let trace: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let args: Vec<_> = trace["traceEvents"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|event| event["ph"] == "X")
+        .map(|event| (event["name"].clone(), event["args"].clone()))
+        .collect();
+    assert_eq!(
+        args,
+        [
+            (
+                serde_json::json!("query"),
+                serde_json::json!({"rows": 42, "cached": false, "tags": ["db", "slow"]})
+            ),
+            (
+                serde_json::json!("dropped"),
+                serde_json::json!({"key": "value"})
+            ),
+        ]
+    );
+}
+
+#[test]
+fn sequence_stamps_count_every_event_of_the_session() {
+    let _serial = serial();
+    Instrumentor::set_stamp_sequence(true);
+    let bytes = record_session("sequence", || {
+        for _ in 0..3 {
+            InstrumentationTimer::new("span");
+        }
+    });
+    Instrumentor::set_stamp_sequence(false);
+    let trace: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let events = trace["traceEvents"].as_array().unwrap();
+    // The thread's name comes first, numbered but not stamped.
+    assert_eq!(events[0]["name"], "thread_name");
+    let stamped: Vec<_> = events
+        .iter()
+        .enumerate()
+        .filter(|(_, event)| event["ph"] == "X")
+        .map(|(i, event)| (i, event["args"]["seq"].as_u64().unwrap() as usize))
+        .collect();
+    assert_eq!(stamped.len(), 3);
+    for (i, seq) in stamped {
+        assert_eq!(seq, i);
+    }
+}
+
+/// Native profiler intervals are begun and ended around spans without
+/// changing what the session records. Off Windows and macOS, or without
+/// their features, enabling them does nothing.
+#[test]
+fn native_profiler_spans_leave_the_session_unchanged() {
+    let _serial = serial();
+    Instrumentor::enable_etw().unwrap();
+    Instrumentor::enable_signposts().unwrap();
+    let bytes = record_session("native", || {
+        let _outer = InstrumentationTimer::new("outer");
+        InstrumentationTimer::new(AWKWARD_NAME);
+        // Disabling doesn't strand the spans already begun.
+        Instrumentor::disable_etw();
+        Instrumentor::disable_signposts();
+    });
+    let spans = Instrumentor::parse_session_bytes(&bytes).unwrap();
+    let names: Vec<_> = spans.iter().map(|span| span.name.as_str()).collect();
+    assert_eq!(names, [AWKWARD_NAME, "outer"]);
+}
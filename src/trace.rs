@@ -0,0 +1,121 @@
+//! Traces built in memory, independently of the global instrumentor.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{self, Write},
+};
+
+use crate::{escape_json, write_chrome_header, ProfileResult, SCHEMA_VERSION};
+
+/// A whole trace held in memory, e.g. in a sandbox without a file system.
+/// Spans are pushed in any order and the trace is serialized once.
+///
+/// With the `serde` feature it's also `Serialize`, as its fields rather than
+/// in a viewer's format, with args as plain values.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Trace {
+    pub name: String,
+    /// Wall clock time of `ts` 0 in microseconds since the Unix epoch, if
+    /// known. Written as the JSON header's `clockSync`.
+    pub unix_micros: Option<i64>,
+    pub events: Vec<ProfileResult>,
+}
+
+impl Trace {
+    pub fn new(name: &str) -> Self {
+        Trace {
+            name: name.to_string(),
+            ..Trace::default()
+        }
+    }
+
+    pub fn push(&mut self, result: ProfileResult) {
+        self.events.push(result);
+    }
+
+    /// The trace in the JSON object format chrome://tracing loads, the same
+    /// a session writes.
+    pub fn to_chrome_string(&self) -> String {
+        let mut out = Vec::new();
+        self.write_chrome(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// The trace as a speedscope evented profile, one profile per thread.
+    /// Spans overlapping the end of their parent are cut off there, since
+    /// speedscope only takes properly nested spans.
+    pub fn to_speedscope_string(&self) -> String {
+        let mut frames: Vec<&str> = Vec::new();
+        let mut frame_ids: HashMap<&str, usize> = HashMap::new();
+        let mut threads: BTreeMap<u32, Vec<&ProfileResult>> = BTreeMap::new();
+        for event in &self.events {
+            threads.entry(event.thread_id).or_default().push(event);
+        }
+
+        let mut profiles = Vec::new();
+        for (thread_id, mut spans) in threads {
+            spans.sort_by_key(|span| (span.start, std::cmp::Reverse(span.end)));
+            let mut events = Vec::new();
+            let mut open: Vec<(usize, i64)> = Vec::new();
+            for span in &spans {
+                while let Some(&(frame, end)) = open.last().filter(|(_, end)| *end <= span.start) {
+                    events.push(format!("{{\"type\":\"C\",\"frame\":{frame},\"at\":{end}}}"));
+                    open.pop();
+                }
+                let frame = *frame_ids.entry(&span.name).or_insert_with(|| {
+                    frames.push(&span.name);
+                    frames.len() - 1
+                });
+                let end = open
+                    .last()
+                    .map_or(span.end, |&(_, parent)| span.end.min(parent));
+                events.push(format!(
+                    "{{\"type\":\"O\",\"frame\":{frame},\"at\":{}}}",
+                    span.start
+                ));
+                open.push((frame, end));
+            }
+            while let Some((frame, end)) = open.pop() {
+                events.push(format!("{{\"type\":\"C\",\"frame\":{frame},\"at\":{end}}}"));
+            }
+            let start = spans.first().map_or(0, |span| span.start);
+            let end = spans.iter().map(|span| span.end).max().unwrap_or(0);
+            profiles.push(format!(
+                "{{\"type\":\"evented\",\"name\":\"{thread_id}\",\"unit\":\"microseconds\",\"startValue\":{start},\"endValue\":{end},\"events\":[{}]}}",
+                events.join(",")
+            ));
+        }
+
+        let frames: Vec<String> = frames
+            .iter()
+            .map(|name| format!("{{\"name\":\"{}\"}}", escape_json(name)))
+            .collect();
+        format!(
+            "{{\"$schema\":\"https://www.speedscope.app/file-format-schema.json\",\"exporter\":\"simple-tracing {}\",\"name\":\"{}\",\"profiles\":[{}],\"shared\":{{\"frames\":[{}]}}}}",
+            env!("CARGO_PKG_VERSION"),
+            escape_json(&self.name),
+            profiles.join(","),
+            frames.join(","),
+        )
+    }
+
+    fn write_chrome(&self, out: &mut impl Write) -> io::Result<()> {
+        write_chrome_header(out, SCHEMA_VERSION, self.unix_micros, false)?;
+        for (i, event) in self.events.iter().enumerate() {
+            if i > 0 {
+                write!(out, ",")?;
+            }
+            event.write_chrome_json(out, "function")?;
+        }
+        write!(out, "]}}")
+    }
+}
+
+/// Writes [`Trace::to_chrome_string`].
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_chrome_string())
+    }
+}
@@ -0,0 +1,8 @@
+//! Where the timing macros and timers compile, and the errors where they don't.
+
+#[test]
+fn ui() {
+    let cases = trybuild::TestCases::new();
+    cases.pass("tests/ui/pass/*.rs");
+    cases.compile_fail("tests/ui/fail/*.rs");
+}
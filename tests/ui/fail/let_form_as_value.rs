@@ -0,0 +1,5 @@
+use simple_tracing::tracing;
+
+fn main() {
+    let _value: i32 = tracing!("value");
+}
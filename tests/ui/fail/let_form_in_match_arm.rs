@@ -0,0 +1,8 @@
+use simple_tracing::tracing;
+
+fn main() {
+    let _ = match 1 {
+        0 => tracing!("zero"),
+        _ => (),
+    };
+}
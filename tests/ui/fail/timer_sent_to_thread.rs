@@ -0,0 +1,6 @@
+use simple_tracing::InstrumentationTimer;
+
+fn main() {
+    let timer = InstrumentationTimer::new("moved");
+    std::thread::spawn(move || drop(timer));
+}
@@ -0,0 +1,11 @@
+use simple_tracing::tracing;
+
+fn main() {
+    let value = {
+        tracing!("block");
+        tracing!("block with args", n = 1);
+        1 + 1
+    };
+    let sum = tracing!("expression" => value + 1);
+    let _ = tracing!("expression with args", n = sum => sum * 2);
+}
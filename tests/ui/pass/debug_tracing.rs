@@ -0,0 +1,12 @@
+use simple_tracing::debug_tracing;
+
+fn main() {
+    debug_tracing!("statement");
+    debug_tracing!("statement with args", n = 1);
+    let value = debug_tracing!("expression" => 1 + 1);
+    let value = debug_tracing!("expression with args", n = value => value * 2);
+    let _ = match value {
+        0 => debug_tracing!("arm" => 0),
+        n => n,
+    };
+}
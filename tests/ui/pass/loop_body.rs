@@ -0,0 +1,15 @@
+use simple_tracing::{tracing, tracing_loop};
+
+fn main() {
+    for i in 0..3 {
+        tracing!("iteration", i = i);
+    }
+    for i in 0..3 {
+        tracing_loop!("indexed", i);
+    }
+    let mut n = 0;
+    while n < 3 {
+        n = tracing!("while" => n + 1);
+    }
+    let _total: i32 = (0..3).map(|i| tracing!("closure" => i)).sum();
+}
@@ -0,0 +1,16 @@
+use simple_tracing::tracing;
+
+fn main() {
+    let len = [1u8, 2, 3].len();
+    let doubled = match Some(len) {
+        Some(n) => tracing!("some", n = n => n * 2),
+        None => tracing!("none" => 0),
+    };
+    let _ = match doubled {
+        0 => {
+            tracing!("braced arm");
+            0
+        }
+        n => n,
+    };
+}